@@ -0,0 +1,660 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use crate::{word_to_symbol, Chains, FthGen, OutBuf, Threading};
+
+/* Everything the generated file needs to run on the host: the
+ * dictionary header layout, the two stacks, and a trampoline style
+ * inner interpreter.  CODE bodies become `void (void)` functions that
+ * end in NEXT (a plain return back into rfc_execute()), and every DOES>
+ * body gets a function of its own that pushes the data and runs the
+ * thread after it.  Primitives
+ * such as lit, exit, branch and qbranch are still expected to come from
+ * the Forth kernel as CODE words, exactly as with the assembler
+ * backends.
+ *
+ * With direct threading a thread holds code addresses instead of
+ * headers: every word gets a `<symbol>_code` function of its own, which
+ * for the high-level words does what its action would have done with
+ * its body, and the inner interpreter just calls the next cell.
+ */
+const RUNTIME: &str = r#"/* Generated by rfc.  Build with any C99 compiler. */
+#include <stddef.h>
+#include <stdint.h>
+
+typedef intptr_t cell;
+typedef struct rfc_word rfc_word;
+
+struct rfc_word {
+    const rfc_word *link;
+    unsigned char flags;
+    unsigned char len;
+    const char *name;
+    void (*code)(void);
+    const cell *body;
+};
+
+#ifndef RFC_STACK_CELLS
+#define RFC_STACK_CELLS 256
+#endif
+
+cell rfc_dstack[RFC_STACK_CELLS];
+cell rfc_rstack[RFC_STACK_CELLS];
+cell *rfc_sp = rfc_dstack + RFC_STACK_CELLS;
+cell *rfc_rp = rfc_rstack + RFC_STACK_CELLS;
+const cell *rfc_ip;
+const rfc_word *rfc_w;
+
+#define PUSH(x) do { cell rfc_t_ = (cell)(x); *--rfc_sp = rfc_t_; } while (0)
+#define POP() (*rfc_sp++)
+#define TOS (rfc_sp[0])
+#define RPUSH(x) do { cell rfc_t_ = (cell)(x); *--rfc_rp = rfc_t_; } while (0)
+#define RPOP() (*rfc_rp++)
+#define NEXT return;
+
+"#;
+
+const RUNTIME_ITC: &str = r#"void rfc_docol(void) { RPUSH(rfc_ip); rfc_ip = rfc_w->body; }
+void rfc_do_const(void) { PUSH(rfc_w->body[0]); }
+void rfc_do_var(void) { PUSH(rfc_w->body); }
+void rfc_do_defer(void) { rfc_w = (const rfc_word *)rfc_w->body[0]; rfc_w->code(); }
+
+/* Run xt until its outermost definition exits. */
+void rfc_execute(const rfc_word *xt)
+{
+    rfc_ip = NULL;
+    rfc_w = xt;
+    xt->code();
+    while (rfc_ip) {
+        rfc_w = (const rfc_word *)*rfc_ip++;
+        rfc_w->code();
+    }
+}
+"#;
+
+const RUNTIME_DTC: &str = r#"typedef void (*rfc_code)(void);
+
+/* Run the code field xt until its outermost definition exits. */
+void rfc_execute(rfc_code xt)
+{
+    rfc_ip = NULL;
+    xt();
+    while (rfc_ip) {
+        ((rfc_code)*rfc_ip++)();
+    }
+}
+"#;
+
+enum Cell {
+    Word(String),
+    Num(i64),
+    Label(String),
+    Str(String),
+    Body(String),
+}
+
+/* A threaded-code array under construction.  C has no labels inside
+ * initializers, so branch targets are resolved to `&array[index]`
+ * when the array is written out.
+ */
+struct Thread {
+    sym: String,
+    cells: Vec<Cell>,
+    labels: HashMap<String, usize>,
+}
+
+impl Thread {
+    fn new(sym: String) -> Self {
+        Thread {
+            sym,
+            cells: Vec::new(),
+            labels: HashMap::new(),
+        }
+    }
+}
+
+/* The body of a data word as `,` and ALLOT lay it down.  A C array
+ * cannot start a cell in the middle of another, so a cell after ALLOT
+ * goes on the next cell boundary, which is where `,` wants HERE anyway.
+ * Indexes and the size are C constant expressions since only the C
+ * compiler knows sizeof(cell).
+ */
+struct DataBody {
+    cells: u64,
+    allots: Vec<(u64, u64)>,
+    init: Vec<(String, Cell)>,
+}
+
+impl DataBody {
+    fn new(reserved: u8, init: Vec<Cell>) -> Self {
+        let mut body = DataBody { cells: reserved as u64, allots: Vec::new(), init: Vec::new() };
+        for c in init {
+            body.push(c);
+        }
+        body
+    }
+
+    fn next_index(&self) -> String {
+        let mut index = self.cells.to_string();
+        for (_, size) in &self.allots {
+            index.push_str(&format!(" + ({size} + sizeof(cell) - 1) / sizeof(cell)"));
+        }
+        index
+    }
+
+    fn push(&mut self, c: Cell) {
+        self.init.push((self.next_index(), c));
+        self.cells += 1;
+    }
+
+    fn allot(&mut self, size: u64) {
+        match self.allots.last_mut() {
+            Some((at, n)) if *at == self.cells => *n += size,
+            _ => self.allots.push((self.cells, size)),
+        }
+    }
+
+    fn size(&self) -> String {
+        if self.allots.is_empty() {
+            /* C has no empty arrays; CREATE alone still gets a cell. */
+            return format!("{} + (0 + sizeof(cell) - 1) / sizeof(cell)", self.cells.max(1));
+        }
+        self.next_index()
+    }
+
+    /* Cells in order are listed as they are, the ones after an ALLOT
+     * with a designator.
+     */
+    fn initializer(&self, xt: impl Fn(&str) -> String) -> String {
+        if self.init.is_empty() {
+            return String::new();
+        }
+        let mut in_order = true;
+        let cells: Vec<String> = self.init.iter().enumerate()
+            .map(|(i, (index, c))| {
+                let v = match c {
+                    Cell::Word(w) => format!("(cell)&{}", xt(w)),
+                    Cell::Num(n) => format!("(cell){n}LL"),
+                    _ => unreachable!(),
+                };
+                in_order = in_order && *index == i.to_string();
+                if in_order { v } else { format!("[{index}] = {v}") }
+            })
+            .collect();
+        format!(" = {{ {} }}", cells.join(", "))
+    }
+}
+
+/* What a word does with its body: the action its ITC header points
+ * to, or the statement its own code function runs with DTC.
+ */
+enum Action {
+    Colon,
+    Const,
+    Var,
+    Defer,
+    Does(String),
+}
+
+impl Action {
+    fn itc(&self) -> String {
+        match self {
+            Action::Colon => String::from("rfc_docol"),
+            Action::Const => String::from("rfc_do_const"),
+            Action::Var => String::from("rfc_do_var"),
+            Action::Defer => String::from("rfc_do_defer"),
+            Action::Does(label) => format!("rfc_does{label}"),
+        }
+    }
+
+    fn dtc(&self, sym: &str) -> String {
+        match self {
+            Action::Colon => format!("RPUSH(rfc_ip); rfc_ip = {sym}_body;"),
+            Action::Const => format!("PUSH({sym}_body[0]);"),
+            Action::Var => format!("PUSH({sym}_body);"),
+            Action::Defer => format!("((rfc_code){sym}_body[0])();"),
+            Action::Does(label) => format!("PUSH({sym}_body); RPUSH(rfc_ip); rfc_ip = rfc_does{label}_body;"),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Header {
+    sym: String,
+    name: String,
+    flags: u8,
+    unlisted: bool,
+}
+
+enum Open {
+    Nothing,
+    Colon(Header, Thread),
+    Code(Header, Vec<String>),
+    Anon(Thread),
+    Does(Thread),
+    Variable(Header, Action, DataBody),
+}
+
+pub struct CGen {
+    threading: Threading,
+    open: Open,
+    declared: HashSet<String>,
+    last_dict_entry: Option<String>,
+    chains: Chains<Option<String>>,
+    next_anon: u32,
+    error: Option<anyhow::Error>,
+    out: OutBuf,
+}
+
+impl CGen {
+    pub fn new(out: Box<dyn Write>, threading: Threading) -> Self {
+        CGen {
+            threading,
+            open: Open::Nothing,
+            declared: HashSet::new(),
+            last_dict_entry: None,
+            chains: Chains::new(None),
+            next_anon: 1,
+            error: None,
+            out: OutBuf::new(out),
+        }
+    }
+
+    fn header(&self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) -> Header {
+        Header {
+            sym: sym.to_string(),
+            name: w.to_string(),
+            flags: if is_immediate { 1 } else { 0 },
+            unlisted,
+        }
+    }
+
+    fn thread(&mut self) -> &mut Thread {
+        if !matches!(self.open, Open::Colon(..) | Open::Anon(_) | Open::Does(_)) {
+            self.flush();
+            let sym = format!("rfc_data_{}", self.next_anon);
+            self.next_anon += 1;
+            self.open = Open::Anon(Thread::new(sym));
+        }
+        match &mut self.open {
+            Open::Colon(_, t) | Open::Anon(t) | Open::Does(t) => t,
+            _ => unreachable!(),
+        }
+    }
+
+    /* What a thread holds for the word `sym`: its header, or with DTC
+     * its code function.
+     */
+    fn xt(&self, sym: &str) -> String {
+        match self.threading {
+            Threading::Dtc => format!("{sym}_code"),
+            _ => sym.to_string(),
+        }
+    }
+
+    fn declare(&mut self, sym: &str) {
+        let xt = self.xt(sym);
+        if self.declared.insert(xt.clone()) {
+            match self.threading {
+                Threading::Dtc => self.out.line(format!("void {xt}(void);")),
+                _ => self.out.line(format!("extern const rfc_word {xt};")),
+            }
+        }
+    }
+
+    /* The code field for a word with `action`.  With DTC that is a
+     * function of the word's own, written here.
+     */
+    fn code_field(&mut self, sym: &str, action: &Action) -> String {
+        if self.threading != Threading::Dtc {
+            return action.itc();
+        }
+        let code = format!("{sym}_code");
+        self.out.line(format!("void {code}(void) {{ {} }}", action.dtc(sym)));
+        code
+    }
+
+    fn write_thread(&mut self, t: &Thread, storage: &str) -> anyhow::Result<()> {
+        for c in &t.cells {
+            if let Cell::Word(sym) = c {
+                self.declare(sym);
+            }
+        }
+        let sym = &t.sym;
+        self.out.line(format!("{storage}const cell {sym}[] = {{"));
+        for c in &t.cells {
+            let v = match c {
+                Cell::Word(w) => format!("(cell)&{}", self.xt(w)),
+                Cell::Num(n) => format!("(cell){n}LL"),
+                Cell::Str(s) => format!("(cell)\"{}\"", c_escape(s)),
+                Cell::Body(w) => format!("(cell){w}_body"),
+                Cell::Label(l) => match t.labels.get(l) {
+                    Some(&i) => match t.cells.get(i) {
+                        Some(Cell::Str(s)) => format!("(cell)\"{}\"", c_escape(s)),
+                        _ => format!("(cell)&{sym}[{i}]"),
+                    },
+                    None => anyhow::bail!("Label {l} is not defined in {sym}"),
+                },
+            };
+            self.out.line(format!("    {v},"));
+        }
+        self.out.line("};");
+
+        Ok(())
+    }
+
+    fn header_line(h: &Header, code: &str, body: &str, link: &str) -> String {
+        let sym = &h.sym;
+        let name = c_escape(&h.name);
+        let len = h.name.len();
+        let flags = h.flags;
+        format!("const rfc_word {sym} = {{ {link}, {flags}, {len}, \"{name}\", {code}, {body} }};")
+    }
+
+    fn write_header(&mut self, h: &Header, code: &str, body: &str) {
+        let link = match &self.last_dict_entry {
+            None => String::from("NULL"),
+            Some(de) => format!("&{de}"),
+        };
+        self.declared.insert(self.xt(&h.sym));
+        self.out.header(CGen::header_line(h, code, body, &link));
+        if !h.unlisted {
+            self.last_dict_entry = Some(h.sym.clone());
+        }
+    }
+
+    /* The first error is kept for epilog(), like OutBuf does with write
+     * errors.
+     */
+    fn flush(&mut self) {
+        if let Err(e) = self.flush_open() {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    fn flush_open(&mut self) -> anyhow::Result<()> {
+        match std::mem::replace(&mut self.open, Open::Nothing) {
+            Open::Nothing => return Ok(()),
+            Open::Anon(t) => {
+                self.write_thread(&t, "static ")?;
+            }
+            Open::Colon(h, mut t) => {
+                self.declare(&h.sym);
+                t.sym = format!("{}_body", h.sym);
+                self.write_thread(&t, "static ")?;
+                let code = self.code_field(&h.sym, &Action::Colon);
+                self.write_header(&h, &code, &t.sym);
+            }
+            Open::Code(h, lines) => {
+                let sym = &h.sym;
+                let storage = if self.threading == Threading::Dtc { "" } else { "static " };
+                self.out.line(format!("{storage}void {sym}_code(void)\n{{"));
+                for l in lines {
+                    self.out.text(l);
+                }
+                self.out.line("}");
+                self.write_header(&h, &format!("{sym}_code"), "NULL");
+            }
+            Open::Does(t) => {
+                self.write_thread(&t, "static ")?;
+                if self.threading == Threading::Dtc {
+                    /* Each word made with it has its own code function. */
+                    self.out.line("");
+                    return Ok(());
+                }
+                let sym = &t.sym;
+                let code = sym.strip_suffix("_body").unwrap_or(sym);
+                self.out.line(format!("static void {code}(void) {{ PUSH(rfc_w->body); RPUSH(rfc_ip); rfc_ip = {sym}; }}"));
+            }
+            Open::Variable(h, action, body) => {
+                let sym = &h.sym;
+                for (_, c) in &body.init {
+                    if let Cell::Word(w) = c {
+                        self.declare(w);
+                    }
+                }
+                let size = body.size();
+                let init = body.initializer(|w| self.xt(w));
+                self.out.line(format!("static cell {sym}_body[{size}]{init};"));
+                let code = self.code_field(sym, &action);
+                self.write_header(&h, &code, &format!("{sym}_body"));
+            }
+        }
+        self.out.line("");
+
+        Ok(())
+    }
+}
+
+fn c_escape(s: &str) -> String {
+    let mut result = String::new();
+
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                result.push('\\');
+                result.push(c);
+            }
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => {
+                result.push_str(&format!("\\{:03o}", c as u32));
+            }
+            c if !c.is_ascii() => {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    result.push_str(&format!("\\{b:03o}"));
+                }
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+impl FthGen for CGen {
+    fn prolog(&mut self) {
+        self.out.text(RUNTIME);
+        match self.threading {
+            Threading::Dtc => self.out.text(RUNTIME_DTC),
+            _ => self.out.text(RUNTIME_ITC),
+        }
+        self.out.line("");
+    }
+
+    fn do_literal(&mut self, n: i64) {
+        let t = self.thread();
+        t.cells.push(Cell::Word(word_to_symbol("lit")));
+        t.cells.push(Cell::Num(n));
+    }
+
+    fn do_string_literal(&mut self, s: &str) {
+        self.thread().cells.push(Cell::Str(s.to_string()));
+    }
+
+    fn create_word(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        self.flush();
+        let h = self.header(w, sym, is_immediate, unlisted);
+        let t = Thread::new(h.sym.clone());
+        self.open = Open::Colon(h, t);
+    }
+
+    fn create_code(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        self.flush();
+        let h = self.header(w, sym, is_immediate, unlisted);
+        self.open = Open::Code(h, Vec::new());
+    }
+
+
+    fn close_definition(&mut self) {
+        self.flush();
+    }
+
+    fn emit_word(&mut self, _w: &str, sym: &str) {
+        self.thread().cells.push(Cell::Word(sym.to_string()));
+    }
+
+    fn emit_cell(&mut self, n: i64) {
+        if let Open::Variable(_, _, body) = &mut self.open {
+            body.push(Cell::Num(n));
+            return;
+        }
+        self.thread().cells.push(Cell::Num(n));
+    }
+
+    fn emit_lines(&mut self, lines: Vec<String>) {
+        match &mut self.open {
+            Open::Code(_, body) => body.extend(lines),
+            _ => {
+                for l in lines {
+                    self.out.text(l);
+                }
+            }
+        }
+    }
+
+    fn refer_to_label(&mut self, w: &str) {
+        self.thread().cells.push(Cell::Label(w.to_string()));
+    }
+
+    fn emit_label(&mut self, l: &str) {
+        let t = self.thread();
+        t.labels.insert(l.to_string(), t.cells.len());
+    }
+
+    fn create_constant(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        self.flush();
+        let h = self.header(name, sym, false, unlisted);
+        self.out.line(format!("static const cell {sym}_body[] = {{ (cell){val}LL }};"));
+        let body = format!("{sym}_body");
+        let code = self.code_field(sym, &Action::Const);
+        self.write_header(&h, &code, &body);
+        self.out.line("");
+    }
+
+    fn create_variable(&mut self, name: &str, sym: &str, size: u8, unlisted: bool) {
+        self.flush();
+        let h = self.header(name, sym, false, unlisted);
+        self.open = Open::Variable(h, Action::Var, DataBody::new(size, Vec::new()));
+    }
+
+    fn set_wordlist(&mut self, head: &str) {
+        self.flush();
+        self.chains.switch(head, &mut self.last_dict_entry);
+    }
+
+    fn create_value(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        self.flush();
+        let h = self.header(name, sym, false, unlisted);
+        self.open = Open::Variable(h, Action::Const, DataBody::new(0, vec![Cell::Num(val)]));
+    }
+
+    fn create_defer(&mut self, name: &str, sym: &str, action: Option<&str>, unlisted: bool) {
+        self.flush();
+        let h = self.header(name, sym, false, unlisted);
+        let xt = action.map_or(Cell::Num(0), |a| Cell::Word(a.to_string()));
+        self.open = Open::Variable(h, Action::Defer, DataBody::new(0, vec![xt]));
+    }
+
+    fn refer_to_body(&mut self, sym: &str) {
+        self.thread().cells.push(Cell::Body(sym.to_string()));
+    }
+
+    fn create_with_action(&mut self, name: &str, sym: &str, action: &str, unlisted: bool) {
+        self.flush();
+        let h = self.header(name, sym, false, unlisted);
+        self.open = Open::Variable(h, Action::Does(action.to_string()), DataBody::new(0, Vec::new()));
+    }
+
+    fn start_does(&mut self, label: &str) {
+        self.flush();
+        self.open = Open::Does(Thread::new(format!("rfc_does{label}_body")));
+    }
+
+    fn allot_space(&mut self, size: u64) {
+        if let Open::Variable(_, _, body) = &mut self.open {
+            body.allot(size);
+            return;
+        }
+        self.flush();
+        let n = self.next_anon;
+        self.next_anon += 1;
+        self.out.line(format!("static char rfc_allot_{n}[{size}];"));
+        self.out.line("");
+    }
+
+    fn epilog(&mut self) -> anyhow::Result<()> {
+        self.flush();
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        for (head, de) in self.chains.all(&self.last_dict_entry) {
+            match de {
+                None => self.out.line(format!("const rfc_word *const {head} = NULL;")),
+                Some(de) => self.out.line(format!("const rfc_word *const {head} = &{de};")),
+            }
+        }
+        self.out.finish()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::out_buf::capture::Capture;
+
+    #[test]
+    fn cells_after_allot_stay_in_the_body() {
+        let cap = Capture::default();
+        let mut g = CGen::new(Box::new(cap.clone()), Threading::Itc);
+        g.create_variable("buf", "w_buf", 0, false);
+        g.emit_cell(1);
+        g.allot_space(3);
+        g.allot_space(2);
+        g.emit_cell(5);
+        g.epilog().unwrap();
+        let text = cap.text();
+        assert!(text.contains("static cell w_buf_body[2 + (5 + sizeof(cell) - 1) / sizeof(cell)] = \
+                               { (cell)1LL, [1 + (5 + sizeof(cell) - 1) / sizeof(cell)] = (cell)5LL };"), "{text}");
+        assert!(!text.contains("rfc_data_"), "{text}");
+    }
+
+    #[test]
+    fn variables_keep_their_own_cells() {
+        let cap = Capture::default();
+        let mut g = CGen::new(Box::new(cap.clone()), Threading::Itc);
+        g.create_variable("v", "w_v", 1, false);
+        g.emit_cell(7);
+        g.create_variable("e", "w_e", 0, false);
+        g.epilog().unwrap();
+        let text = cap.text();
+        assert!(text.contains("static cell w_v_body[2 + (0 + sizeof(cell) - 1) / sizeof(cell)] = { [1] = (cell)7LL };"), "{text}");
+        assert!(text.contains("static cell w_e_body[1 + (0 + sizeof(cell) - 1) / sizeof(cell)];"), "{text}");
+    }
+
+    #[test]
+    fn dtc_threads_code_functions() {
+        let cap = Capture::default();
+        let mut g = CGen::new(Box::new(cap.clone()), Threading::Dtc);
+        g.prolog();
+        g.create_variable("v", "w_v", 1, false);
+        g.create_word("f", "w_f", false, false);
+        g.emit_word("v", "w_v");
+        g.emit_word("exit", "w_exit");
+        g.close_definition();
+        g.epilog().unwrap();
+        let text = cap.text();
+        for line in ["void rfc_execute(rfc_code xt)",
+                     "void w_v_code(void) { PUSH(w_v_body); }",
+                     "const rfc_word w_v = { NULL, 0, 1, \"v\", w_v_code, w_v_body };",
+                     "void w_exit_code(void);",
+                     "    (cell)&w_v_code,",
+                     "void w_f_code(void) { RPUSH(rfc_ip); rfc_ip = w_f_body; }",
+                     "const rfc_word w_f = { &w_v, 0, 1, \"f\", w_f_code, w_f_body };"] {
+            assert!(text.contains(line), "{line} not in {text}");
+        }
+        assert!(!text.contains("rfc_docol"), "{text}");
+    }
+}
@@ -0,0 +1,254 @@
+use std::io::Write;
+
+use crate::{ref_str, tass_text, Ca6502, Chains, FthGen, OutBuf, Threading};
+
+/* 64tass output for a native-mode 65816 kernel.  The header macros are
+ * the same .HIGH_W/.CODE_W pair the 6502 kernel uses, but cell
+ * references can be 24 bits wide (`.long`) so the dictionary may cross
+ * bank boundaries, and data cells may be 32 bits.  Every CODE body
+ * starts with .al/.xl since NEXT runs with 16-bit registers; a body
+ * that drops to 8 bits leaves the assembler's idea of M/X behind it,
+ * which would otherwise leak into the next body.
+ *
+ * With `--threading dtc` the code field is a call to the action, JSL
+ * when references are 24 bits wide and JSR otherwise.
+ */
+pub struct Ca65816 {
+    last_dict_entry: String,
+    chains: Chains<String>,
+    addr_bits: u8,
+    cell_bits: u8,
+    threading: Threading,
+    out: OutBuf,
+}
+
+impl Ca65816 {
+    pub fn new(out: Box<dyn Write>, addr_bits: u8, cell_bits: u8, threading: Threading) -> Self {
+        Ca65816 {
+            last_dict_entry: String::from("0"),
+            chains: Chains::new(String::from("0")),
+            addr_bits,
+            cell_bits,
+            threading,
+            out: OutBuf::new(out),
+        }
+    }
+
+    fn addr_dir(&self) -> &'static str {
+        if self.addr_bits == 24 { ".long" } else { ".addr" }
+    }
+
+    fn call_action(&mut self, action: &str) {
+        if self.threading == Threading::Dtc {
+            let op = if self.addr_bits == 24 { "JSL" } else { "JSR" };
+            self.out.line(format!("    {op} {action}"));
+        }
+    }
+
+    fn cell(&self, n: i64) -> String {
+        if self.cell_bits == 32 {
+            let l = n as i32;
+            if l < 0 {
+                format!("    .dint {l}")
+            } else {
+                format!("    .dword {l}")
+            }
+        } else {
+            let l = n as i16;
+            if l < 0 {
+                format!("    .sint {l}")
+            } else {
+                format!("    .word {l}")
+            }
+        }
+    }
+}
+
+impl FthGen for Ca65816 {
+    fn prolog(&mut self) {
+    }
+
+    fn do_literal(&mut self, n: i64) {
+        let dir = self.addr_dir();
+        self.out.line(format!("    {dir} w_lit.cfa"));
+        self.out.line(self.cell(n));
+    }
+
+    fn do_string_literal(&mut self, s: &str) {
+        self.out.line(format!("    .text {}", tass_text(s)));
+    }
+
+    fn create_word(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        let last_ref = ref_str(&self.last_dict_entry);
+        let kind = if self.threading == Threading::Dtc { "CODE_W" } else { "HIGH_W" };
+        self.out.header(Ca6502::word_header(kind, w, sym, is_immediate, &last_ref));
+        self.out.line("  .block");
+        self.call_action("w_docol");
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn create_code(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        let last_ref = ref_str(&self.last_dict_entry);
+        self.out.header(Ca6502::word_header("CODE_W", w, sym, is_immediate, &last_ref));
+        self.out.line("  .block");
+        self.out.line("    .al");
+        self.out.line("    .xl");
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+
+    fn close_definition(&mut self) {
+        self.out.line("  .endblock");
+    }
+
+    fn emit_word(&mut self, _w: &str, sym: &str) {
+        let sym_ref = ref_str(sym);
+        let dir = self.addr_dir();
+        self.out.line(format!("    {dir} {sym_ref}"));
+    }
+
+    fn emit_cell(&mut self, n: i64) {
+        self.out.line(self.cell(n));
+    }
+
+    fn emit_lines(&mut self, lines: Vec<String>) {
+        for l in lines {
+            self.out.text(l);
+        }
+    }
+
+    fn refer_to_label(&mut self, w: &str) {
+        let dir = self.addr_dir();
+        self.out.line(format!("    {dir} {w}"));
+    }
+
+    fn emit_label(&mut self, l: &str) {
+        self.out.line(l);
+    }
+
+    fn create_constant(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        let last_ref = ref_str(&self.last_dict_entry);
+        self.out.header(Ca6502::data_header(name, sym, "w_const", self.threading, &last_ref));
+        self.call_action("w_const");
+        self.out.line(self.cell(val));
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn create_variable(&mut self, name: &str, sym: &str, size: u8, unlisted: bool) {
+        let last_ref = ref_str(&self.last_dict_entry);
+        self.out.header(Ca6502::data_header(name, sym, "w_var", self.threading, &last_ref));
+        self.call_action("w_var");
+        for _ in 0..size {
+            self.out.line(self.cell(0));
+        }
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn set_wordlist(&mut self, head: &str) {
+        self.chains.switch(head, &mut self.last_dict_entry);
+    }
+
+    fn create_value(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        let last_ref = ref_str(&self.last_dict_entry);
+        self.out.header(Ca6502::data_header(name, sym, "w_const", self.threading, &last_ref));
+        self.call_action("w_const");
+        self.out.line(format!("{sym}_body"));
+        self.out.line(self.cell(val));
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn create_defer(&mut self, name: &str, sym: &str, action: Option<&str>, unlisted: bool) {
+        let last_ref = ref_str(&self.last_dict_entry);
+        self.out.header(Ca6502::data_header(name, sym, "w_defer", self.threading, &last_ref));
+        self.call_action("w_defer");
+        self.out.line(format!("{sym}_body"));
+        let dir = self.addr_dir();
+        let xt = action.map_or_else(|| String::from("0"), ref_str);
+        self.out.line(format!("    {dir} {xt}"));
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn refer_to_body(&mut self, sym: &str) {
+        let body = format!("{sym}_body");
+        self.refer_to_label(&body);
+    }
+
+    fn create_with_action(&mut self, name: &str, sym: &str, action: &str, unlisted: bool) {
+        let last_ref = ref_str(&self.last_dict_entry);
+        self.out.header(Ca6502::data_header(name, sym, action, self.threading, &last_ref));
+        self.call_action(action);
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn start_does(&mut self, label: &str) {
+        let op = if self.addr_bits == 24 { "JSL" } else { "JSR" };
+        self.out.line(label);
+        self.out.line("  .block");
+        self.out.line(format!("    {op} w_dodoes"));
+    }
+
+    fn allot_space(&mut self, size: u64) {
+        self.out.line(format!("    .fill {size}"));
+    }
+
+    fn epilog(&mut self) -> anyhow::Result<()> {
+        let dir = self.addr_dir();
+        for (head, de) in self.chains.all(&self.last_dict_entry) {
+            self.out.line(format!("{head} {dir} {}", ref_str(&de)));
+        }
+        self.out.finish()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::emit_with;
+
+    const SRC: &str = "\
+CODE dup ( a -- a a )
+    sep #$20
+END-CODE
+: t ( -- a b c ) 70000 -1 dup ;
+VARIABLE v
+";
+
+    #[test]
+    fn wide_references_and_cells() {
+        let asm = emit_with(SRC, &["-a", "ca65816", "--addr-bits", "24", "--cell-bits", "32"]).unwrap();
+        assert!(asm.contains("    .al\n    .xl\n    sep #$20\n    NEXT\n"), "{asm}");
+        assert!(asm.contains("    .long w_lit.cfa\n    .dword 70000\n    .long w_lit.cfa\n    .dint -1\n"), "{asm}");
+        assert!(asm.contains("w_v    .HIGH_W 1, \"V\", w_var, , w_t.cfa\n    .dword 0\n"), "{asm}");
+        assert!(asm.contains("dict_head .long w_v.cfa"), "{asm}");
+    }
+
+    #[test]
+    fn narrow_references_and_cells() {
+        let asm = emit_with(SRC, &["-a", "ca65816"]).unwrap();
+        assert!(asm.contains("    .addr w_lit.cfa\n    .sint -1\n"), "{asm}");
+        assert!(!asm.contains(".long"), "{asm}");
+    }
+
+    #[test]
+    fn dtc_code_fields_call_the_action() {
+        let asm = emit_with(SRC, &["-a", "ca65816", "-t", "dtc", "--addr-bits", "24"]).unwrap();
+        assert!(asm.contains("JSL w_docol"), "{asm}");
+        let asm = emit_with(SRC, &["-a", "ca65816", "-t", "dtc"]).unwrap();
+        assert!(asm.contains("JSR w_docol"), "{asm}");
+    }
+}
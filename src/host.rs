@@ -0,0 +1,568 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use anyhow::Context;
+
+use crate::{parse_number, w_allot, w_backslash, w_dot_paren, w_paren, Fth, FthAction, ACTIVE_WORDS};
+
+/* Host words are colon definitions that run inside rfc rather than on
+ * the target.  They are introduced with NEXT_HOST and compiled into the
+ * small threaded form below.  When one is executed it works on
+ * Fth::data_stack and can reach the generator through `,`, LITERAL,
+ * POSTPONE and the ordinary compiling words (IF, CONSTANT, ...).
+ *
+ * HOST_PRIMS are also what rfc runs for those names in interpret
+ * state, so `W H * CONSTANT AREA` is computed before CONSTANT sees it.
+ *
+ * A host word with DOES> is a defining word.  Only the part before
+ * DOES> runs inside rfc; the rest is compiled for the target as the
+ * action of the word CREATE made.
+ */
+#[derive(Debug)]
+pub enum HostOp {
+    Lit(i64),
+    Call(String),
+    Postpone(String),
+    Branch(usize),
+    QBranch(usize),
+    Do,
+    QDo(usize),
+    Loop(usize),
+    PlusLoop(usize),
+    Leave(usize),
+    Unloop,
+    Does(String),
+    Exit,
+}
+
+pub type HostWord = Rc<Vec<HostOp>>;
+
+lazy_static! {
+    static ref HOST_PRIMS: HashMap<&'static str, FthAction> = {
+        let mut m = HashMap::new();
+
+        m.insert(",", h_comma as FthAction);
+        m.insert("ALLOT", w_allot as FthAction);
+        m.insert("CELLS", h_cells as FthAction);
+        m.insert("CELL+", h_cell_plus as FthAction);
+        m.insert("+", h_plus as FthAction);
+        m.insert("-", h_minus as FthAction);
+        m.insert("*", h_star as FthAction);
+        m.insert("/", h_slash as FthAction);
+        m.insert("MOD", h_mod as FthAction);
+        m.insert("AND", h_and as FthAction);
+        m.insert("OR", h_or as FthAction);
+        m.insert("XOR", h_xor as FthAction);
+        m.insert("INVERT", h_invert as FthAction);
+        m.insert("NEGATE", h_negate as FthAction);
+        m.insert("LSHIFT", h_lshift as FthAction);
+        m.insert("RSHIFT", h_rshift as FthAction);
+        m.insert("DUP", h_dup as FthAction);
+        m.insert("DROP", h_drop as FthAction);
+        m.insert("SWAP", h_swap as FthAction);
+        m.insert("OVER", h_over as FthAction);
+        m.insert("ROT", h_rot as FthAction);
+        m.insert("=", h_equals as FthAction);
+        m.insert("<", h_less as FthAction);
+        m.insert(">", h_greater as FthAction);
+        m.insert("0=", h_zero_equals as FthAction);
+        m.insert("NOT", h_zero_equals as FthAction);
+
+        m
+    };
+}
+
+fn pop(fth: &mut Fth, for_word: &str) -> anyhow::Result<i64> {
+    match fth.data_stack.pop() {
+        None => anyhow::bail!("Stack underflow for {for_word}"),
+        Some(v) => Ok(v),
+    }
+}
+
+fn h_comma(fth: &mut Fth) -> anyhow::Result<()> {
+    let v = pop(fth, ",")?;
+    fth.ir.emit_cell(v);
+
+    Ok(())
+}
+
+fn cell_size(fth: &Fth, for_word: &str) -> anyhow::Result<i64> {
+    fth.cell_size.with_context(|| format!("{for_word} needs one cell size, but the arches given have several"))
+}
+
+fn h_cells(fth: &mut Fth) -> anyhow::Result<()> {
+    let n = cell_size(fth, "CELLS")?;
+    let a = pop(fth, "CELLS")?;
+    fth.data_stack.push(a.wrapping_mul(n));
+
+    Ok(())
+}
+
+fn h_cell_plus(fth: &mut Fth) -> anyhow::Result<()> {
+    let n = cell_size(fth, "CELL+")?;
+    let a = pop(fth, "CELL+")?;
+    fth.data_stack.push(a.wrapping_add(n));
+
+    Ok(())
+}
+
+fn flag(b: bool) -> i64 {
+    if b { -1 } else { 0 }
+}
+
+fn unary_op(fth: &mut Fth, name: &str, f: fn(i64) -> i64) -> anyhow::Result<()> {
+    let a = pop(fth, name)?;
+    fth.data_stack.push(f(a));
+
+    Ok(())
+}
+
+fn binary_op(fth: &mut Fth, name: &str, f: fn(i64, i64) -> i64) -> anyhow::Result<()> {
+    let b = pop(fth, name)?;
+    let a = pop(fth, name)?;
+    fth.data_stack.push(f(a, b));
+
+    Ok(())
+}
+
+fn h_plus(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, "+", i64::wrapping_add)
+}
+
+fn h_minus(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, "-", i64::wrapping_sub)
+}
+
+fn h_star(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, "*", i64::wrapping_mul)
+}
+
+fn h_slash(fth: &mut Fth) -> anyhow::Result<()> {
+    if fth.data_stack.last() == Some(&0) {
+        anyhow::bail!("Division by zero for /");
+    }
+    binary_op(fth, "/", i64::wrapping_div)
+}
+
+fn h_mod(fth: &mut Fth) -> anyhow::Result<()> {
+    if fth.data_stack.last() == Some(&0) {
+        anyhow::bail!("Division by zero for MOD");
+    }
+    binary_op(fth, "MOD", i64::wrapping_rem)
+}
+
+fn h_and(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, "AND", |a, b| a & b)
+}
+
+fn h_or(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, "OR", |a, b| a | b)
+}
+
+fn h_xor(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, "XOR", |a, b| a ^ b)
+}
+
+fn h_invert(fth: &mut Fth) -> anyhow::Result<()> {
+    unary_op(fth, "INVERT", |a| !a)
+}
+
+fn h_negate(fth: &mut Fth) -> anyhow::Result<()> {
+    unary_op(fth, "NEGATE", i64::wrapping_neg)
+}
+
+fn h_lshift(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, "LSHIFT", |a, n| {
+        if (0..64).contains(&n) { a << n } else { 0 }
+    })
+}
+
+fn h_rshift(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, "RSHIFT", |a, n| {
+        if (0..64).contains(&n) { ((a as u64) >> n) as i64 } else { 0 }
+    })
+}
+
+fn h_dup(fth: &mut Fth) -> anyhow::Result<()> {
+    let a = pop(fth, "DUP")?;
+    fth.data_stack.push(a);
+    fth.data_stack.push(a);
+
+    Ok(())
+}
+
+fn h_drop(fth: &mut Fth) -> anyhow::Result<()> {
+    pop(fth, "DROP")?;
+
+    Ok(())
+}
+
+fn h_swap(fth: &mut Fth) -> anyhow::Result<()> {
+    let b = pop(fth, "SWAP")?;
+    let a = pop(fth, "SWAP")?;
+    fth.data_stack.push(b);
+    fth.data_stack.push(a);
+
+    Ok(())
+}
+
+fn h_over(fth: &mut Fth) -> anyhow::Result<()> {
+    let b = pop(fth, "OVER")?;
+    let a = pop(fth, "OVER")?;
+    fth.data_stack.push(a);
+    fth.data_stack.push(b);
+    fth.data_stack.push(a);
+
+    Ok(())
+}
+
+fn h_rot(fth: &mut Fth) -> anyhow::Result<()> {
+    let c = pop(fth, "ROT")?;
+    let b = pop(fth, "ROT")?;
+    let a = pop(fth, "ROT")?;
+    fth.data_stack.push(b);
+    fth.data_stack.push(c);
+    fth.data_stack.push(a);
+
+    Ok(())
+}
+
+fn h_equals(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, "=", |a, b| flag(a == b))
+}
+
+fn h_less(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, "<", |a, b| flag(a < b))
+}
+
+fn h_greater(fth: &mut Fth) -> anyhow::Result<()> {
+    binary_op(fth, ">", |a, b| flag(a > b))
+}
+
+fn h_zero_equals(fth: &mut Fth) -> anyhow::Result<()> {
+    unary_op(fth, "0=", |a| flag(a == 0))
+}
+
+pub fn is_host_word(fth: &Fth, upper_w: &str) -> bool {
+    fth.host_words.contains_key(upper_w) || HOST_PRIMS.contains_key(upper_w)
+}
+
+enum Ctrl {
+    If(usize),
+    Else(usize),
+    Begin(usize),
+    While(usize, usize),
+    Do(usize, Vec<usize>),
+}
+
+/* Compile the body of a NEXT_HOST colon definition, reading straight
+ * from the input up to the closing semicolon or DOES>, after `ops`.
+ */
+pub fn define(fth: &mut Fth, name: &str, mut ops: Vec<HostOp>) -> anyhow::Result<()> {
+    let upper_name = name.to_uppercase();
+    let mut ctrl: Vec<Ctrl> = Vec::new();
+
+    loop {
+        fth.input_mgr.skip_ws()?;
+        let w = match fth.input_mgr.word()? {
+            None => anyhow::bail!("EOF while defining host word {name}"),
+            Some(w) => w,
+        };
+        let upper_w = w.to_uppercase();
+        let here = ops.len();
+        match &*upper_w {
+            ";" => break,
+            "DOES>" => {
+                if !ctrl.is_empty() {
+                    anyhow::bail!("DOES> inside a control structure in host word {name}");
+                }
+                let label = fth.new_label();
+                ops.push(HostOp::Does(label.clone()));
+                fth.host_words.insert(upper_name, Rc::new(ops));
+                fth.ir.start_does(&label);
+                fth.is_compiling = true;
+                fth.open_def = Some((name.to_string(), fth.input_mgr.location()));
+                return Ok(());
+            }
+            "(" => w_paren(fth)?,
+            "\\" => w_backslash(fth)?,
+            ".(" => w_dot_paren(fth)?,
+            "IF" => {
+                ctrl.push(Ctrl::If(here));
+                ops.push(HostOp::QBranch(0));
+            }
+            "ELSE" => {
+                let Some(Ctrl::If(orig)) = ctrl.pop() else {
+                    anyhow::bail!("Missing IF for ELSE in host word {name}");
+                };
+                ops.push(HostOp::Branch(0));
+                ops[orig] = HostOp::QBranch(here + 1);
+                ctrl.push(Ctrl::Else(here));
+            }
+            "THEN" => {
+                match ctrl.pop() {
+                    Some(Ctrl::If(orig)) => ops[orig] = HostOp::QBranch(here),
+                    Some(Ctrl::Else(orig)) => ops[orig] = HostOp::Branch(here),
+                    _ => anyhow::bail!("Missing IF/ELSE for THEN in host word {name}"),
+                }
+            }
+            "BEGIN" => ctrl.push(Ctrl::Begin(here)),
+            "WHILE" => {
+                let Some(Ctrl::Begin(dest)) = ctrl.pop() else {
+                    anyhow::bail!("Missing BEGIN for WHILE in host word {name}");
+                };
+                ops.push(HostOp::QBranch(0));
+                ctrl.push(Ctrl::While(dest, here));
+            }
+            "REPEAT" => {
+                let Some(Ctrl::While(dest, orig)) = ctrl.pop() else {
+                    anyhow::bail!("Missing WHILE for REPEAT in host word {name}");
+                };
+                ops.push(HostOp::Branch(dest));
+                ops[orig] = HostOp::QBranch(here + 1);
+            }
+            "UNTIL" | "AGAIN" => {
+                let Some(Ctrl::Begin(dest)) = ctrl.pop() else {
+                    anyhow::bail!("Missing BEGIN for {upper_w} in host word {name}");
+                };
+                if upper_w == "UNTIL" {
+                    ops.push(HostOp::QBranch(dest));
+                } else {
+                    ops.push(HostOp::Branch(dest));
+                }
+            }
+            "DO" => {
+                ops.push(HostOp::Do);
+                ctrl.push(Ctrl::Do(here + 1, Vec::new()));
+            }
+            "?DO" => {
+                ops.push(HostOp::QDo(0));
+                ctrl.push(Ctrl::Do(here + 1, vec![here]));
+            }
+            "LEAVE" => {
+                let Some(Ctrl::Do(_, leaves)) = ctrl.iter_mut().rev().find(|c| matches!(c, Ctrl::Do(..))) else {
+                    anyhow::bail!("Missing DO for LEAVE in host word {name}");
+                };
+                leaves.push(here);
+                ops.push(HostOp::Leave(0));
+            }
+            "LOOP" | "+LOOP" => {
+                let Some(Ctrl::Do(dest, leaves)) = ctrl.pop() else {
+                    anyhow::bail!("Missing DO for {upper_w} in host word {name}");
+                };
+                if upper_w == "LOOP" {
+                    ops.push(HostOp::Loop(dest));
+                } else {
+                    ops.push(HostOp::PlusLoop(dest));
+                }
+                for l in leaves {
+                    ops[l] = match ops[l] {
+                        HostOp::QDo(_) => HostOp::QDo(here + 1),
+                        _ => HostOp::Leave(here + 1),
+                    };
+                }
+            }
+            "UNLOOP" => ops.push(HostOp::Unloop),
+            "EXIT" => ops.push(HostOp::Exit),
+            "RECURSE" => ops.push(HostOp::Call(upper_name.clone())),
+            "POSTPONE" => {
+                fth.input_mgr.skip_ws()?;
+                match fth.input_mgr.word()? {
+                    None => anyhow::bail!("EOF after POSTPONE in host word {name}"),
+                    Some(p) => ops.push(HostOp::Postpone(p)),
+                }
+            }
+            _ => match parse_number(&w)? {
+                Some(n) => ops.push(HostOp::Lit(n)),
+                None => ops.push(HostOp::Call(w)),
+            },
+        }
+    }
+
+    if !ctrl.is_empty() {
+        anyhow::bail!("Unbalanced control structure in host word {name}");
+    }
+    fth.host_words.insert(upper_name, Rc::new(ops));
+
+    Ok(())
+}
+
+/* Run the host word or host primitive `w`.  Words that are neither are
+ * looked up in ACTIVE_WORDS, so a host word may use IF, CONSTANT and
+ * friends exactly as source text would, and then among the CONSTANTs
+ * defined so far.
+ */
+pub fn execute(fth: &mut Fth, w: &str) -> anyhow::Result<()> {
+    let upper_w = w.to_uppercase();
+    let ops = match fth.host_words.get(&upper_w) {
+        Some(ops) => ops.clone(),
+        None => {
+            if let Some(action) = HOST_PRIMS.get(&*upper_w) {
+                return action(fth);
+            }
+            if let Some(action) = ACTIVE_WORDS.get(&*upper_w) {
+                return action(fth);
+            }
+            if let Some(&v) = fth.constants.get(&upper_w) {
+                fth.data_stack.push(v);
+                return Ok(());
+            }
+            anyhow::bail!("Unknown word '{w}' in host code");
+        }
+    };
+    let mut loops: Vec<(i64, i64)> = Vec::new();
+    let mut pc = 0;
+
+    while pc < ops.len() {
+        let mut next_pc = pc + 1;
+        match &ops[pc] {
+            HostOp::Lit(n) => fth.data_stack.push(*n),
+            HostOp::Call(c) => match c.to_uppercase().as_str() {
+                "I" | "J" => {
+                    let depth = if c.eq_ignore_ascii_case("I") { 1 } else { 2 };
+                    if loops.len() < depth {
+                        anyhow::bail!("{c} used outside of a DO loop in host word {w}");
+                    }
+                    fth.data_stack.push(loops[loops.len() - depth].1);
+                }
+                _ => execute(fth, c)?,
+            },
+            HostOp::Postpone(p) => {
+                let upper_p = p.to_uppercase();
+                if fth.host_words.contains_key(&upper_p) || ACTIVE_WORDS.contains_key(&*upper_p) {
+                    execute(fth, p)?;
+                } else {
+                    fth.emit_word(p);
+                }
+            }
+            HostOp::Branch(dest) => next_pc = *dest,
+            HostOp::QBranch(dest) => {
+                if pop(fth, w)? == 0 {
+                    next_pc = *dest;
+                }
+            }
+            HostOp::Do => {
+                let start = pop(fth, "DO")?;
+                let limit = pop(fth, "DO")?;
+                loops.push((limit, start));
+            }
+            HostOp::QDo(dest) => {
+                let start = pop(fth, "?DO")?;
+                let limit = pop(fth, "?DO")?;
+                if start == limit {
+                    next_pc = *dest;
+                } else {
+                    loops.push((limit, start));
+                }
+            }
+            HostOp::Loop(dest) | HostOp::PlusLoop(dest) => {
+                let step = match ops[pc] {
+                    HostOp::Loop(_) => 1,
+                    _ => pop(fth, "+LOOP")?,
+                };
+                let Some((limit, index)) = loops.last_mut() else {
+                    anyhow::bail!("LOOP without a loop frame in host word {w}");
+                };
+                let before = index.wrapping_sub(*limit);
+                *index = index.wrapping_add(step);
+                let after = index.wrapping_sub(*limit);
+                if (before ^ after) < 0 {
+                    loops.pop();
+                } else {
+                    next_pc = *dest;
+                }
+            }
+            HostOp::Leave(dest) => {
+                loops.pop();
+                next_pc = *dest;
+            }
+            HostOp::Unloop => {
+                loops.pop();
+            }
+            HostOp::Does(label) => {
+                fth.ir.set_action(label)?;
+                break;
+            }
+            HostOp::Exit => break,
+        }
+        pc = next_pc;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{Header, Item};
+    use crate::testing::{body_of, compile_with, names};
+
+    #[test]
+    fn host_words_run_at_compile_time() {
+        let src = "\
+NEXT_HOST : squares ( n -- ) 0 DO I I * , LOOP ;
+VARIABLE sq_table 4 squares
+";
+        let prog = compile_with(src, &[]).unwrap();
+        assert_eq!(body_of(&prog, "sq_table"), [Item::Cell(0), Item::Cell(1), Item::Cell(4), Item::Cell(9)]);
+    }
+
+    #[test]
+    fn host_words_postpone_into_the_target_word() {
+        let src = "\
+NEXT_HOST : UNLESS POSTPONE 0= POSTPONE IF ;
+: t ( x -- ) UNLESS DROP THEN ;
+";
+        let prog = compile_with(src, &["--allow-undefined"]).unwrap();
+        assert_eq!(
+            body_of(&prog, "t"),
+            [
+                Item::Word("0=".into(), 0),
+                Item::Word("qbranch".into(), 0),
+                Item::LabelRef("_L001".into()),
+                Item::Word("DROP".into(), 0),
+                Item::Label("_L001".into()),
+                Item::Word("exit".into(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn unbalanced_host_words_are_rejected() {
+        let Err(e) = compile_with("NEXT_HOST : bad ELSE ;\n", &[]) else {
+            panic!("ELSE without IF was accepted");
+        };
+        assert!(format!("{e:#}").contains("Missing IF for ELSE in host word bad"), "{e:#}");
+    }
+
+    #[test]
+    fn interpret_state_arithmetic_feeds_constants_and_conditionals() {
+        let src = "\
+40 25 * CONSTANT size
+7 2 MOD 3 SWAP - NEGATE CONSTANT neg
+1 4 LSHIFT 1 RSHIFT 0= INVERT CONSTANT truth
+[DEFINED] A [DEFINED] B AND [IF] : both ( -- ) ; [THEN]
+[DEFINED] A [DEFINED] C AND [IF] : neither ( -- ) ; [THEN]
+";
+        let prog = compile_with(src, &["-d", "A,B"]).unwrap();
+        let val = |name: &str| match prog.defs.iter().filter_map(|d| d.header.as_ref()).find(|h| h.name() == name) {
+            Some(Header::Constant { val, .. }) => *val,
+            h => panic!("{name}: {h:?}"),
+        };
+        assert_eq!(val("size"), 1000);
+        assert_eq!(val("neg"), -2);
+        assert_eq!(val("truth"), -1);
+        assert_eq!(names(&prog), ["size", "neg", "truth", "both"]);
+    }
+
+    #[test]
+    fn interpret_state_errors_are_reported() {
+        let Err(e) = compile_with("1 0 / CONSTANT x\n", &[]) else {
+            panic!("division by zero was accepted");
+        };
+        assert!(format!("{e:#}").contains("Division by zero for /"), "{e:#}");
+        let Err(e) = compile_with("1 + CONSTANT x\n", &[]) else {
+            panic!("stack underflow was accepted");
+        };
+        assert!(format!("{e:#}").contains("Stack underflow for +"), "{e:#}");
+    }
+}
@@ -1,28 +1,272 @@
-use anyhow;
+use anyhow::Context;
+use clap::ValueEnum;
+use std::collections::HashSet;
+use std::fmt;
 use std::fs::File;
 use std::mem;
-use std::io::{Read, BufReader, BufRead};
+use std::io::{BufReader, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub file: Arc<str>,
+    pub line: u32,
+    pub col: u32,
+    pub included_from: Option<Arc<Location>>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/* A compile error (or warning) tied to the place in the source that
+ * caused it.  Displays as `file:line:col: error: msg`, followed by one
+ * line per INCLUDE that led to that file.  Without a place (a word that
+ * only appears on the command line, say) it is `rfc: error: msg`.
+ */
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub loc: Option<Location>,
+    pub severity: Severity,
+    pub msg: String,
+}
+
+impl Diagnostic {
+    pub fn error(loc: impl Into<Option<Location>>, msg: String) -> Self {
+        Diagnostic { loc: loc.into(), severity: Severity::Error, msg }
+    }
+
+    pub fn warning(loc: impl Into<Option<Location>>, msg: String) -> Self {
+        Diagnostic { loc: loc.into(), severity: Severity::Warning, msg }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(loc) = &self.loc else {
+            return write!(f, "rfc: {}: {}", self.severity, self.msg);
+        };
+        write!(f, "{loc}: {}: {}", self.severity, self.msg)?;
+        let mut inc = &loc.included_from;
+        while let Some(l) = inc {
+            write!(f, "\n    included from {l}")?;
+            inc = &l.included_from;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/* Several diagnostics reported together, one per line. */
+#[derive(Debug)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, d) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{d}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+/* How source bytes turn into chars. */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Encoding {
+    Utf8,
+    Latin1,
+}
+
+/* What to do with non-ASCII chars in words and string literals.
+ * `escape` keeps them and leaves it to each backend to write their
+ * UTF-8 bytes in its assembler's syntax; `transliterate` replaces them
+ * with the nearest ASCII, where there is one; `error` rejects them.
+ * Comments, `.(` and CODE bodies are never touched.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum NonAscii {
+    Escape,
+    Transliterate,
+    Error,
+}
+
+fn transliterate(c: char) -> Option<&'static str> {
+    let s = match c {
+        'À'..='Å' => "A",
+        'Æ' => "AE",
+        'Ç' => "C",
+        'È'..='Ë' => "E",
+        'Ì'..='Ï' => "I",
+        'Ð' => "D",
+        'Ñ' => "N",
+        'Ò'..='Ö' | 'Ø' => "O",
+        '×' => "x",
+        'Ù'..='Ü' => "U",
+        'Ý' | 'Ÿ' => "Y",
+        'Þ' => "TH",
+        'ß' => "ss",
+        'à'..='å' => "a",
+        'æ' => "ae",
+        'ç' => "c",
+        'è'..='ë' => "e",
+        'ì'..='ï' => "i",
+        'ð' => "d",
+        'ñ' => "n",
+        'ò'..='ö' | 'ø' => "o",
+        '÷' => "/",
+        'ù'..='ü' => "u",
+        'ý' | 'ÿ' => "y",
+        'þ' => "th",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Š' => "S",
+        'š' => "s",
+        'Ž' => "Z",
+        'ž' => "z",
+        '\u{a0}' => " ",
+        '‘' | '’' | '‚' => "'",
+        '“' | '”' | '„' => "\"",
+        '–' | '—' => "-",
+        '…' => "...",
+        '«' => "<<",
+        '»' => ">>",
+        '©' => "(C)",
+        '®' => "(R)",
+        _ => return None,
+    };
+    Some(s)
+}
+
+fn decode(encoding: Encoding, bytes: Vec<u8>) -> Result<String, Vec<u8>> {
+    match encoding {
+        Encoding::Utf8 => String::from_utf8(bytes).map_err(|e| e.into_bytes()),
+        Encoding::Latin1 => Ok(bytes.iter().map(|&b| char::from(b)).collect()),
+    }
+}
+
+/* Input is read a line at a time into `line_buf`; `pos` is how much of
+ * it has been handed out.
+ */
+struct Source {
+    name: Arc<str>,
+    dir: PathBuf,
+    reader: BufReader<File>,
+    line_buf: Vec<u8>,
+    pos: usize,
+    line: u32,
+    col: u32,
+    included_from: Option<Arc<Location>>,
+}
+
+/* Files named by INCLUDE and REQUIRE are looked for next to the file
+ * doing the including, then in each `-I` directory, then in the
+ * current directory.  `included` holds every file opened so far, so
+ * REQUIRE can leave out the ones already read.
+ */
 pub struct InputMgr {
-    input_readers: Vec<BufReader<File>>,
+    input_readers: Vec<Source>,
+    include_dirs: Vec<PathBuf>,
+    included: HashSet<PathBuf>,
+    encoding: Encoding,
+    non_ascii: NonAscii,
     last_chars: String,
+    char_loc: Option<Location>,
+    word_loc: Option<Location>,
 }
 
 impl InputMgr {
-    pub fn new() -> Self {
+    pub fn new(include_dirs: Vec<PathBuf>, encoding: Encoding, non_ascii: NonAscii) -> Self {
         InputMgr {
             input_readers: Vec::new(),
+            include_dirs,
+            included: HashSet::new(),
+            encoding,
+            non_ascii,
             last_chars: String::new(),
+            char_loc: None,
+            word_loc: None,
         }
     }
 
     pub fn open_file(&mut self, filename: &str) -> anyhow::Result<()> {
-        let f = File::open(filename)?;
+        let f = File::open(filename).with_context(|| format!("Cannot open '{filename}'"))?;
         let r = BufReader::new(f);
-        self.input_readers.push(r);
+        let path = Path::new(filename);
+        self.included.insert(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+        self.input_readers.push(Source {
+            name: Arc::from(filename),
+            dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            reader: r,
+            line_buf: Vec::new(),
+            pos: 0,
+            line: 1,
+            col: 1,
+            included_from: self.word_loc.clone().map(Arc::new),
+        });
         Ok(())
     }
 
+    fn find_file(&self, filename: &str) -> anyhow::Result<PathBuf> {
+        let path = Path::new(filename);
+        if path.is_absolute() {
+            return Ok(path.to_path_buf());
+        }
+        let mut dirs: Vec<&Path> = Vec::new();
+        if let Some(src) = self.input_readers.last() {
+            dirs.push(&src.dir);
+        }
+        dirs.extend(self.include_dirs.iter().map(PathBuf::as_path));
+        for d in &dirs {
+            let p = d.join(path);
+            if p.is_file() {
+                return Ok(p);
+            }
+        }
+        if path.is_file() {
+            return Ok(path.to_path_buf());
+        }
+        let mut tried: Vec<String> = dirs.iter().map(|d| d.join(path).display().to_string()).collect();
+        tried.push(filename.to_string());
+        tried.dedup();
+        anyhow::bail!("Cannot find '{filename}' (tried {})", tried.join(", "));
+    }
+
+    /* INCLUDE (once == false) and REQUIRE (once == true). */
+    pub fn include(&mut self, filename: &str, once: bool) -> anyhow::Result<()> {
+        let path = self.find_file(filename)?;
+        if once {
+            let canon = path.canonicalize().unwrap_or_else(|_| path.clone());
+            if self.included.contains(&canon) {
+                return Ok(());
+            }
+        }
+        self.open_file(&path.to_string_lossy())
+    }
+
     pub fn close_current(&mut self) -> anyhow::Result<bool> {
         match self.input_readers.pop() {
             None => Ok(false),
@@ -32,6 +276,11 @@ impl InputMgr {
         }
     }
 
+    /* Where the most recently skipped-to word starts. */
+    pub fn location(&self) -> Option<Location> {
+        self.word_loc.clone()
+    }
+
     fn next_char(&mut self) -> anyhow::Result<Option<char>> {
         let mut chars = self.last_chars.chars();
         if let Some(r_char) = chars.next() {
@@ -41,24 +290,82 @@ impl InputMgr {
         'read_loop: loop {
             match self.input_readers.last_mut() {
                 None => return Ok(None),
-                Some(reader) => {
-                    let mut read_buf = [0; 1];
-                    let n_read = reader.read(&mut read_buf)?;
-                    if n_read == 0 {
-                        self.close_current()?;
+                Some(src) => {
+                    if src.pos >= src.line_buf.len() {
+                        src.line_buf.clear();
+                        src.pos = 0;
+                        if src.reader.read_until(b'\n', &mut src.line_buf)? == 0 {
+                            self.close_current()?;
+                        }
                         continue 'read_loop;
                     } else {
-                        return Ok(Some(char::from(read_buf[0])))
+                        let loc = Location {
+                            file: src.name.clone(),
+                            line: src.line,
+                            col: src.col,
+                            included_from: src.included_from.clone(),
+                        };
+                        let b = src.line_buf[src.pos];
+                        let len = match (self.encoding, b) {
+                            (Encoding::Latin1, _) | (_, 0..=0x7f) => 1,
+                            (_, 0xc0..=0xdf) => 2,
+                            (_, 0xe0..=0xef) => 3,
+                            (_, 0xf0..=0xf7) => 4,
+                            _ => 0,
+                        };
+                        let c = if len == 1 {
+                            char::from(b)
+                        } else {
+                            let bytes = src.line_buf.get(src.pos..src.pos + len).unwrap_or_default();
+                            match std::str::from_utf8(bytes).ok().and_then(|s| s.chars().next()) {
+                                Some(c) => c,
+                                None => {
+                                    let msg = format!("Invalid UTF-8 (byte 0x{b:02x}); try --encoding latin1");
+                                    return Err(Diagnostic::error(loc, msg).into());
+                                }
+                            }
+                        };
+                        src.pos += len;
+                        self.char_loc = Some(loc);
+                        if c == '\n' {
+                            src.line += 1;
+                            src.col = 1;
+                        } else {
+                            src.col += 1;
+                        }
+                        return Ok(Some(c))
                     }
                 }
             }
         }
     }
 
+    /* Throw away the rest of the current line, for `\`.  Nothing to do
+     * if the word before it already took the newline.
+     */
+    pub fn skip_line(&mut self) {
+        self.last_chars.clear();
+        if let Some(src) = self.input_readers.last_mut() {
+            if src.pos < src.line_buf.len() {
+                if src.line_buf.ends_with(b"\n") {
+                    src.line += 1;
+                    src.col = 1;
+                }
+                src.pos = src.line_buf.len();
+            }
+        }
+    }
+
+    /* Whether the line of the last word still has unread text on it. */
+    pub fn mid_line(&self) -> bool {
+        !self.last_chars.is_empty()
+            || self.input_readers.last().is_some_and(|src| src.pos < src.line_buf.len())
+    }
+
     pub fn lines_until(&mut self, end_marker: &str) -> anyhow::Result<Vec<String>> {
         let mut r_lines = Vec::new();
 
-        if self.last_chars.len() > 0 {
+        if !self.last_chars.is_empty() {
             // FIXME: figure out how to use string.take()
             let mut new_str = String::new();
             mem::swap(&mut new_str, &mut self.last_chars);
@@ -70,13 +377,34 @@ impl InputMgr {
                 None => {
                     return Ok(r_lines);
                 }
-                Some(reader) => {
-                    let mut read_buf = String::new();
-                    let n_read = reader.read_line(&mut read_buf)?;
+                Some(src) => {
+                    let mut read_buf = Vec::new();
+                    let n_read = if src.pos < src.line_buf.len() {
+                        read_buf.extend_from_slice(&src.line_buf[src.pos..]);
+                        src.pos = src.line_buf.len();
+                        read_buf.len()
+                    } else {
+                        src.reader.read_until(b'\n', &mut read_buf)?
+                    };
+                    let read_buf = match decode(self.encoding, read_buf) {
+                        Ok(s) => s,
+                        Err(_) => {
+                            let loc = Location {
+                                file: src.name.clone(),
+                                line: src.line,
+                                col: 1,
+                                included_from: src.included_from.clone(),
+                            };
+                            let msg = String::from("Invalid UTF-8; try --encoding latin1");
+                            return Err(Diagnostic::error(loc, msg).into());
+                        }
+                    };
                     if n_read == 0 {
                         self.close_current()?;
                         continue 'read_loop;
                     } else {
+                        src.line += 1;
+                        src.col = 1;
                         if read_buf.starts_with(end_marker) {
                             return Ok(r_lines);
                         }
@@ -92,9 +420,10 @@ impl InputMgr {
             match self.next_char()? {
                 None => return Ok(()),
                 Some(c) => {
-                    if (c as char).is_whitespace() {
+                    if c.is_whitespace() {
                         continue
                     }
+                    self.word_loc = self.char_loc.clone();
                     self.last_chars = String::from(c);
                     return Ok(());
                 }
@@ -108,7 +437,7 @@ impl InputMgr {
         loop {
             match self.next_char()? {
                 None => {
-                    if r_str.len() > 0 {
+                    if !r_str.is_empty() {
                         return Ok(Some(r_str));
                     } else {
                         return Ok(None);
@@ -127,7 +456,109 @@ impl InputMgr {
     }
 
     pub fn word(&mut self) -> anyhow::Result<Option<String>> {
+        match self.str_by(|c: char| c.is_whitespace())? {
+            None => Ok(None),
+            Some(w) => Ok(Some(self.text(w)?)),
+        }
+    }
+
+    /* A word taken as it is, such as a file name. */
+    pub fn raw_word(&mut self) -> anyhow::Result<Option<String>> {
         self.str_by(|c: char| c.is_whitespace())
     }
+
+    /* Apply the --non-ascii policy to a word or string literal. */
+    pub fn text(&self, s: String) -> anyhow::Result<String> {
+        if s.is_ascii() || self.non_ascii == NonAscii::Escape {
+            return Ok(s);
+        }
+        let mut result = String::new();
+        for c in s.chars() {
+            if c.is_ascii() {
+                result.push(c);
+                continue;
+            }
+            match (self.non_ascii, transliterate(c)) {
+                (NonAscii::Transliterate, Some(t)) => result.push_str(t),
+                (NonAscii::Transliterate, None) => {
+                    anyhow::bail!("'{c}' (U+{:04X}) in '{s}' has no ASCII transliteration", c as u32)
+                }
+                _ => anyhow::bail!("Non-ASCII character '{c}' (U+{:04X}) in '{s}'", c as u32),
+            }
+        }
+        Ok(result)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::testing::{compile_file, names, Scratch};
+
+    #[test]
+    fn includes_are_found_next_to_the_including_file_first() {
+        let dir = Scratch::new();
+        dir.file("lib/a.fs", ": a ( -- ) ;\nINCLUDE b.fs\n");
+        dir.file("lib/b.fs", ": lib_b ( -- ) ;\n");
+        dir.file("b.fs", ": top_b ( -- ) ;\n");
+        dir.file("inc/c.fs", ": c ( -- ) ;\n");
+        let main = dir.file("main.fs", "INCLUDE lib/a.fs\nINCLUDE b.fs\nINCLUDE c.fs\n");
+        let prog = compile_file(&main, &["-I", &dir.path("inc")]).unwrap();
+        assert_eq!(names(&prog), ["a", "lib_b", "top_b", "c"]);
+    }
+
+    #[test]
+    fn require_reads_a_file_once() {
+        let dir = Scratch::new();
+        dir.file("lib/once.fs", "CODE once ( -- )\nEND-CODE\n");
+        dir.file("lib/user.fs", "REQUIRE once.fs\n: user ( -- ) once ;\n");
+        let main = dir.file("main.fs", "REQUIRE lib/once.fs\nREQUIRE lib/user.fs\nREQUIRE lib/../lib/once.fs\n");
+        let prog = compile_file(&main, &[]).unwrap();
+        assert_eq!(names(&prog), ["once", "user"]);
+
+        let main = dir.file("twice.fs", "INCLUDE lib/once.fs\nINCLUDE lib/once.fs\n");
+        let prog = compile_file(&main, &[]).unwrap();
+        assert_eq!(names(&prog), ["once", "once"]);
+    }
+
+    #[test]
+    fn errors_name_the_include_chain() {
+        let dir = Scratch::new();
+        dir.file("bad.fs", ": bad ( -- ) THEN ;\n");
+        let main = dir.file("main.fs", "INCLUDE bad.fs\n");
+        let e = format!("{:#}", compile_file(&main, &[]).err().unwrap());
+        assert!(e.contains("bad.fs:1:14: error: THEN without IF"), "{e}");
+        assert!(e.contains(&format!("\n    included from {main}:1:9")), "{e}");
+
+        let main = dir.file("missing.fs", "INCLUDE nope.fs\n");
+        let e = format!("{:#}", compile_file(&main, &["-I", &dir.path("inc")]).err().unwrap());
+        assert!(e.contains("Cannot find 'nope.fs' (tried "), "{e}");
+        assert!(e.contains(&dir.path("inc/nope.fs")), "{e}");
+    }
+
+    fn compile_bytes(text: &[u8], opts: &[&str]) -> anyhow::Result<Vec<String>> {
+        let dir = Scratch::new();
+        let prog = compile_file(&dir.file("main.fs", text), opts)?;
+        Ok(names(&prog).into_iter().map(String::from).collect())
+    }
+
+    #[test]
+    fn source_is_decoded_as_utf8_or_latin1() {
+        assert_eq!(compile_bytes(": café ( -- ) ;\n".as_bytes(), &[]).unwrap(), ["café"]);
+        assert_eq!(compile_bytes(b": caf\xe9 ( -- ) ;\n", &["--encoding", "latin1"]).unwrap(), ["café"]);
+
+        let e = format!("{:#}", compile_bytes(b": caf\xe9 ( -- ) ;\n", &[]).err().unwrap());
+        assert!(e.contains("main.fs:1:6: error: Invalid UTF-8 (byte 0xe9); try --encoding latin1"), "{e}");
+        let e = format!("{:#}", compile_bytes(b"CODE c ( -- )\n    ; caf\xe9\nEND-CODE\n", &[]).err().unwrap());
+        assert!(e.contains("main.fs:2:1: error: Invalid UTF-8; try --encoding latin1"), "{e}");
+    }
+
+    #[test]
+    fn non_ascii_policies() {
+        let src = ": café ( -- ) ;\n".as_bytes();
+        assert_eq!(compile_bytes(src, &["--non-ascii", "transliterate"]).unwrap(), ["cafe"]);
+        let e = format!("{:#}", compile_bytes(src, &["--non-ascii", "error"]).err().unwrap());
+        assert!(e.contains("Non-ASCII character 'é' (U+00E9) in 'café'"), "{e}");
+        let e = format!("{:#}", compile_bytes(": a→b ( -- ) ;\n".as_bytes(), &["--non-ascii", "transliterate"]).err().unwrap());
+        assert!(e.contains("'→' (U+2192) in 'a→b' has no ASCII transliteration"), "{e}");
+    }
+}
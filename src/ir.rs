@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{word_symbol, word_to_symbol, FthGen};
+
+/* The program as the front end saw it, before any backend is involved.
+ * Fth fills a Builder while it interprets the source; the passes
+ * (--strip-unused, the peephole rules) work on the finished Program and
+ * emit() then drives an FthGen with it, once per --arch.
+ *
+ * A Def is one definition and everything compiled into it.  Colon and
+ * CODE definitions end at close_definition(); the data following a
+ * CONSTANT, VARIABLE or CREATE (ALLOT, `,`) belongs to it until the next
+ * header.  A CREATE word gets its action once DOES> has run; the thread
+ * after DOES> is a Def of its own that starts with the label the action
+ * refers to.  Anything else outside a definition (the preamble, top-level VERBATIM
+ * blocks, ...) goes into a Def without a header.
+ *
+ * Every Def remembers the wordlist that was current for it, an index
+ * into Program::wordlists, which holds the head symbol of each chain.
+ * Words compiled into a body (and the targets of TO, IS and a DEFER's
+ * action) come with the wordlist their name was found in, FORTH for
+ * the kernel's, which word_symbol() needs to name their label.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Header {
+    Colon { name: String, immediate: bool, unlisted: bool },
+    Code { name: String, immediate: bool, unlisted: bool },
+    Constant { name: String, val: i64, unlisted: bool },
+    Variable { name: String, size: u8, unlisted: bool },
+    Value { name: String, val: i64, unlisted: bool },
+    Defer { name: String, action: Option<(String, usize)>, unlisted: bool },
+    Create { name: String, action: Option<String>, unlisted: bool },
+    Does { label: String },
+}
+
+impl Header {
+    pub fn name(&self) -> &str {
+        match self {
+            Header::Colon { name, .. } |
+            Header::Code { name, .. } |
+            Header::Constant { name, .. } |
+            Header::Variable { name, .. } |
+            Header::Value { name, .. } |
+            Header::Defer { name, .. } |
+            Header::Create { name, .. } => name,
+            Header::Does { label } => label,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Literal(i64),
+    Str(String),
+    Word(String, usize),
+    Cell(i64),
+    Asm(Vec<String>),
+    LabelRef(String),
+    Label(String),
+    Allot(u64),
+    BodyRef(String, usize),
+}
+
+/* An item of a Def, by index: (def, item). */
+pub type At = (usize, usize);
+
+#[derive(Clone, Debug)]
+pub struct Def {
+    pub header: Option<Header>,
+    pub body: Vec<Item>,
+    pub closed: bool,
+    pub wordlist: usize,
+}
+
+impl Def {
+    fn is_open(&self) -> bool {
+        matches!(self.header, Some(Header::Colon { .. } | Header::Code { .. } | Header::Does { .. })) && !self.closed
+    }
+}
+
+#[derive(Default)]
+pub struct Program {
+    pub defs: Vec<Def>,
+    pub wordlists: Vec<String>,
+}
+
+impl Program {
+    pub fn emit(&self, g: &mut dyn FthGen) -> anyhow::Result<()> {
+        g.prolog();
+        let mut wordlist = 0;
+        for d in &self.defs {
+            if d.header.is_some() && d.wordlist != wordlist {
+                wordlist = d.wordlist;
+                g.set_wordlist(&self.wordlists[wordlist]);
+            }
+            let sym = |name: &str| word_symbol(d.wordlist, name);
+            match &d.header {
+                None => {}
+                Some(Header::Colon { name, immediate, unlisted }) => g.create_word(name, &sym(name), *immediate, *unlisted),
+                Some(Header::Code { name, immediate, unlisted }) => g.create_code(name, &sym(name), *immediate, *unlisted),
+                Some(Header::Constant { name, val, unlisted }) => g.create_constant(name, &sym(name), *val, *unlisted),
+                Some(Header::Variable { name, size, unlisted }) => g.create_variable(name, &sym(name), *size, *unlisted),
+                Some(Header::Value { name, val, unlisted }) => g.create_value(name, &sym(name), *val, *unlisted),
+                Some(Header::Defer { name, action, unlisted }) => {
+                    let action = action.as_ref().map(|(a, wid)| word_symbol(*wid, a));
+                    g.create_defer(name, &sym(name), action.as_deref(), *unlisted)
+                }
+                Some(Header::Create { name, action: None, unlisted }) => g.create_variable(name, &sym(name), 0, *unlisted),
+                Some(Header::Create { name, action: Some(a), unlisted }) => g.create_with_action(name, &sym(name), a, *unlisted),
+                Some(Header::Does { label }) => g.start_does(label),
+            }
+            for item in &d.body {
+                match item {
+                    Item::Literal(n) => g.do_literal(*n),
+                    Item::Str(s) => g.do_string_literal(s),
+                    Item::Word(w, wid) => g.emit_word(w, &word_symbol(*wid, w)),
+                    Item::Cell(n) => g.emit_cell(*n),
+                    Item::Asm(lines) => g.emit_lines(lines.clone()),
+                    Item::LabelRef(l) => g.refer_to_label(l),
+                    Item::Label(l) => g.emit_label(l),
+                    Item::Allot(n) => g.allot_space(*n),
+                    Item::BodyRef(w, wid) => g.refer_to_body(&word_symbol(*wid, w)),
+                }
+            }
+            if d.closed {
+                g.close_definition();
+            }
+        }
+        /* Empty wordlists still get a head. */
+        for head in self.wordlists.iter().skip(1) {
+            g.set_wordlist(head);
+        }
+        g.epilog()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut st = Stats::default();
+        let mut uses: HashMap<&str, usize> = HashMap::new();
+        for d in &self.defs {
+            match &d.header {
+                None => {}
+                Some(Header::Colon { .. } | Header::Does { .. }) => st.colon += 1,
+                Some(Header::Code { .. }) => st.code += 1,
+                Some(Header::Constant { .. }) => st.constants += 1,
+                Some(Header::Variable { .. } | Header::Value { .. } |
+                     Header::Defer { .. } | Header::Create { .. }) => st.variables += 1,
+            }
+            for item in &d.body {
+                match item {
+                    Item::Word(w, _) => {
+                        st.cells += 1;
+                        *uses.entry(w.as_str()).or_default() += 1;
+                    }
+                    Item::Literal(_) => st.cells += 2,
+                    Item::Cell(_) | Item::LabelRef(_) | Item::BodyRef(..) => st.cells += 1,
+                    Item::Str(s) => st.string_bytes += s.len(),
+                    Item::Allot(n) => st.allot_bytes += n,
+                    Item::Asm(_) | Item::Label(_) => {}
+                }
+            }
+        }
+        st.distinct_words = uses.len();
+        let mut top: Vec<(&str, usize)> = uses.into_iter().collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        st.most_used = top.into_iter().take(5).map(|(w, n)| (w.to_string(), n)).collect();
+        st
+    }
+}
+
+#[derive(Default)]
+pub struct Stats {
+    colon: usize,
+    code: usize,
+    constants: usize,
+    variables: usize,
+    cells: usize,
+    string_bytes: usize,
+    allot_bytes: u64,
+    distinct_words: usize,
+    most_used: Vec<(String, usize)>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "definitions: {} colon, {} code, {} constants, {} variables",
+                 self.colon, self.code, self.constants, self.variables)?;
+        writeln!(f, "threaded cells: {}, string bytes: {}, allotted bytes: {}",
+                 self.cells, self.string_bytes, self.allot_bytes)?;
+        write!(f, "distinct words compiled: {}", self.distinct_words)?;
+        if !self.most_used.is_empty() {
+            let top: Vec<String> = self.most_used.iter().map(|(w, n)| format!("{w} ({n})")).collect();
+            write!(f, "\nmost used: {}", top.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/* Collects a Program from the same calls an FthGen would get. */
+#[derive(Default)]
+pub struct Builder {
+    prog: Program,
+    wordlist: usize,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    pub fn finish(mut self, wordlists: Vec<String>) -> Program {
+        self.prog.wordlists = wordlists;
+        self.prog
+    }
+
+    fn start(&mut self, header: Option<Header>) {
+        self.prog.defs.push(Def { header, body: Vec::new(), closed: false, wordlist: self.wordlist });
+    }
+
+    pub fn set_wordlist(&mut self, wordlist: usize) {
+        self.wordlist = wordlist;
+    }
+
+    fn push(&mut self, item: Item) {
+        if self.prog.defs.last().is_none_or(|d| d.closed) {
+            self.start(None);
+        }
+        self.prog.defs.last_mut().unwrap().body.push(item);
+    }
+
+    pub fn do_literal(&mut self, n: i64) {
+        self.push(Item::Literal(n));
+    }
+
+    pub fn do_string_literal(&mut self, s: &str) {
+        self.push(Item::Str(s.to_string()));
+    }
+
+    pub fn create_word(&mut self, w: &str, is_immediate: bool, unlisted: bool) {
+        self.start(Some(Header::Colon { name: w.to_string(), immediate: is_immediate, unlisted }));
+    }
+
+    pub fn create_code(&mut self, w: &str, is_immediate: bool, unlisted: bool) {
+        self.start(Some(Header::Code { name: w.to_string(), immediate: is_immediate, unlisted }));
+    }
+
+    /* IMMEDIATE after `;` applies to the last word defined, even if
+     * data outside any definition (a top-level S" or VERBATIM) came
+     * after it.
+     */
+    pub fn make_immediate(&mut self) -> anyhow::Result<()> {
+        match self.prog.defs.iter_mut().rev().find_map(|d| d.header.as_mut()) {
+            Some(Header::Colon { immediate, .. } | Header::Code { immediate, .. }) => {
+                *immediate = true;
+                Ok(())
+            }
+            _ => anyhow::bail!("IMMEDIATE must follow a colon or CODE definition"),
+        }
+    }
+
+    pub fn close_definition(&mut self) {
+        match self.prog.defs.last_mut() {
+            Some(d) if d.is_open() => d.closed = true,
+            _ => {}
+        }
+    }
+
+    pub fn emit_word(&mut self, w: &str, wordlist: usize) {
+        self.push(Item::Word(w.to_string(), wordlist));
+    }
+
+    /* Where the item pushed last is, for rebind(). */
+    pub fn last_item(&self) -> At {
+        let d = self.prog.defs.len() - 1;
+        (d, self.prog.defs[d].body.len() - 1)
+    }
+
+    /* A word compiled before its definition was seen turned out to be
+     * in `wordlist`.
+     */
+    pub fn rebind(&mut self, (d, i): At, wordlist: usize) {
+        if let Some(Item::Word(_, wid)) = self.prog.defs.get_mut(d).and_then(|d| d.body.get_mut(i)) {
+            *wid = wordlist;
+        }
+    }
+
+    pub fn emit_cell(&mut self, n: i64) {
+        self.push(Item::Cell(n));
+    }
+
+    pub fn emit_lines(&mut self, lines: Vec<String>) {
+        if !self.prog.defs.last().is_some_and(Def::is_open) {
+            self.start(None);
+        }
+        self.push(Item::Asm(lines));
+    }
+
+    pub fn refer_to_label(&mut self, w: &str) {
+        self.push(Item::LabelRef(w.to_string()));
+    }
+
+    pub fn emit_label(&mut self, l: &str) {
+        self.push(Item::Label(l.to_string()));
+    }
+
+    pub fn create_constant(&mut self, name: &str, val: i64, unlisted: bool) {
+        self.start(Some(Header::Constant { name: name.to_string(), val, unlisted }));
+    }
+
+    pub fn create_variable(&mut self, name: &str, size: u8, unlisted: bool) {
+        self.start(Some(Header::Variable { name: name.to_string(), size, unlisted }));
+    }
+
+    pub fn create_value(&mut self, name: &str, val: i64, unlisted: bool) {
+        self.start(Some(Header::Value { name: name.to_string(), val, unlisted }));
+    }
+
+    /* TO outside a definition changes what the VALUE starts out as.
+     * Only the latest VALUE of that name is the one the source sees.
+     */
+    pub fn set_value(&mut self, name: &str, wordlist: usize, new_val: i64) {
+        for d in self.prog.defs.iter_mut().rev() {
+            if let Some(Header::Value { name: n, val, .. }) = &mut d.header {
+                if d.wordlist == wordlist && word_to_symbol(n) == word_to_symbol(name) {
+                    *val = new_val;
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn create_defer(&mut self, name: &str, unlisted: bool) {
+        self.start(Some(Header::Defer { name: name.to_string(), action: None, unlisted }));
+    }
+
+    /* IS outside a definition sets the word the DEFER starts out
+     * executing, the same way set_value() does for a VALUE.
+     */
+    pub fn set_defer(&mut self, name: &str, wordlist: usize, word: (String, usize)) {
+        for d in self.prog.defs.iter_mut().rev() {
+            if let Some(Header::Defer { name: n, action, .. }) = &mut d.header {
+                if d.wordlist == wordlist && word_to_symbol(n) == word_to_symbol(name) {
+                    *action = Some(word);
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn refer_to_body(&mut self, w: &str, wordlist: usize) {
+        self.push(Item::BodyRef(w.to_string(), wordlist));
+    }
+
+    pub fn create(&mut self, name: &str, unlisted: bool) {
+        self.start(Some(Header::Create { name: name.to_string(), action: None, unlisted }));
+    }
+
+    /* DOES> gives the word CREATE made last the action `label`. */
+    pub fn set_action(&mut self, label: &str) -> anyhow::Result<()> {
+        match self.prog.defs.last_mut().and_then(|d| d.header.as_mut()) {
+            Some(Header::Create { action, .. }) => {
+                *action = Some(label.to_string());
+                Ok(())
+            }
+            _ => anyhow::bail!("DOES> needs a word made by CREATE"),
+        }
+    }
+
+    pub fn start_does(&mut self, label: &str) {
+        self.start(Some(Header::Does { label: label.to_string() }));
+    }
+
+    /* Take back a colon definition nothing has been compiled into yet. */
+    pub fn drop_empty_colon(&mut self) -> bool {
+        match self.prog.defs.last() {
+            Some(d) if d.is_open() && d.body.is_empty() && matches!(d.header, Some(Header::Colon { .. })) => {
+                self.prog.defs.pop();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn allot_space(&mut self, size: u64) {
+        self.push(Item::Allot(size));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wordlists::FORTH;
+
+    #[test]
+    fn immediate_skips_headerless_data() {
+        let mut b = Builder::new();
+        b.create_word("foo", false, false);
+        b.emit_word("exit", FORTH);
+        b.close_definition();
+        b.do_string_literal("x");
+        b.make_immediate().unwrap();
+        let prog = b.finish(Vec::new());
+        assert_eq!(prog.defs.len(), 2);
+        assert_eq!(prog.defs[0].header, Some(Header::Colon { name: "foo".to_string(), immediate: true, unlisted: false }));
+    }
+
+    #[test]
+    fn immediate_needs_a_word() {
+        let mut b = Builder::new();
+        b.do_string_literal("x");
+        assert!(b.make_immediate().is_err());
+        b.create_constant("k", 1, false);
+        assert!(b.make_immediate().is_err());
+    }
+
+    #[test]
+    fn initial_values_go_to_the_latest_word() {
+        let mut b = Builder::new();
+        b.create_value("v", 1, false);
+        b.create_defer("d", false);
+        b.create_value("v", 2, false);
+        b.create_defer("d", false);
+        b.set_value("v", FORTH, 5);
+        b.set_defer("d", FORTH, ("emit".to_string(), FORTH));
+        let headers: Vec<Header> = b.finish(Vec::new()).defs.into_iter().filter_map(|d| d.header).collect();
+        assert_eq!(headers, vec![
+            Header::Value { name: "v".to_string(), val: 1, unlisted: false },
+            Header::Defer { name: "d".to_string(), action: None, unlisted: false },
+            Header::Value { name: "v".to_string(), val: 5, unlisted: false },
+            Header::Defer { name: "d".to_string(), action: Some(("emit".to_string(), FORTH)), unlisted: false },
+        ]);
+    }
+}
@@ -1,21 +1,94 @@
-use anyhow;
 #[macro_use]
 extern crate lazy_static;
 use std::collections::{HashMap, HashSet};
+use anyhow::Context;
 use clap::{Parser, ValueEnum};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::rc::Rc;
 
 mod input_mgr;
-use input_mgr::InputMgr;
+use input_mgr::{Diagnostic, Diagnostics, Encoding, InputMgr, Location, NonAscii};
+mod out_buf;
+use out_buf::OutBuf;
+mod c_gen;
+use c_gen::CGen;
+mod ca65816;
+use ca65816::Ca65816;
+mod z80;
+use z80::Z80Gen;
+mod riscv32;
+use riscv32::Rv32Gen;
+mod host;
+use host::{HostOp, HostWord};
+mod ir;
+use ir::Program;
+mod shake;
+mod peephole;
+mod symbols;
+use symbols::{SymKind, SymbolTable};
+mod stack_check;
+use stack_check::StackCheck;
+mod wordlists;
+use wordlists::{SearchOrder, FORTH, SEARCH_WORDS};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about=None)]
 struct Args {
-    #[arg(short, long, value_enum)]
-    arch: Arch,
+    #[arg(short, long, value_enum, value_delimiter=',', required=true,
+          help="Target(s); with more than one, -o names get the arch before the extension")]
+    arch: Vec<Arch>,
 
     #[arg(short, long)]
     defines: Option<String>,
 
+    #[arg(short, long, help="Output file (default: stdout)")]
+    output: Option<String>,
+
+    #[arg(short, long, value_enum, default_value_t=Threading::Itc)]
+    threading: Threading,
+
+    #[arg(long, help="Only emit words reachable from these comma-separated roots")]
+    strip_unused: Option<String>,
+
+    #[arg(long, help="Rewrite colon definitions with the built-in peephole rules")]
+    peephole: bool,
+
+    #[arg(long, help="Rewrite colon definitions with the peephole rules in this file")]
+    peephole_rules: Option<String>,
+
+    #[arg(long, help="Do not reject references to words that are neither defined nor EXTERN")]
+    allow_undefined: bool,
+
+    #[arg(long, value_enum, default_value_t=StackCheck::Warn,
+          help="Check colon definitions against their ( -- ) stack-effect comments")]
+    stack_check: StackCheck,
+
+    #[arg(long, help="Print statistics about the compiled program to stderr")]
+    stats: bool,
+
+    #[arg(long, help="Write the token assignment to this file (--threading token)")]
+    token_map: Option<String>,
+
+    #[arg(long, help="Width of cell references: 16 (default) or 24 (ca65816 only)")]
+    addr_bits: Option<u8>,
+
+    #[arg(long, help="Width of data cells: 16 (default) or 32 (ca65816 only)")]
+    cell_bits: Option<u8>,
+
+    #[arg(short='I', long="include-dir", value_name="DIR",
+          help="Look here for INCLUDE and REQUIRE files not next to the including file")]
+    include_dirs: Vec<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t=Encoding::Utf8, help="Encoding of the source files")]
+    encoding: Encoding,
+
+    #[arg(long, value_enum, default_value_t=NonAscii::Escape,
+          help="What to do with non-ASCII characters in words and strings")]
+    non_ascii: NonAscii,
+
     #[arg(help="Forth source file")]
     filename: String,
 }
@@ -24,7 +97,19 @@ struct Args {
 enum Arch {
     C,
     AttAsm32,
+    AttAsm64,
     Ca6502,
+    Ca65816,
+    Z80,
+    Riscv32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Threading {
+    Itc,
+    Dtc,
+    Stc,
+    Token,
 }
 
 type FthAction = fn(&mut Fth) -> anyhow::Result<()>;
@@ -73,10 +158,21 @@ lazy_static! {
         m.insert(":", w_colon as FthAction);
         m.insert(";", w_semicolon as FthAction);
         m.insert("CODE", w_code as FthAction);
+        m.insert("[", w_left_bracket as FthAction);
+        m.insert("]", w_right_bracket as FthAction);
         m.insert("(", w_paren as FthAction);
+        m.insert("\\", w_backslash as FthAction);
+        m.insert(".(", w_dot_paren as FthAction);
         m.insert("CONSTANT", w_constant as FthAction);
         m.insert("VARIABLE", w_variable as FthAction);
         m.insert("2VARIABLE", w_2variable as FthAction);
+        m.insert("VALUE", w_value as FthAction);
+        m.insert("TO", w_to as FthAction);
+        m.insert("DEFER", w_defer as FthAction);
+        m.insert("IS", w_is as FthAction);
+        m.insert("'", w_tick as FthAction);
+        m.insert("CREATE", w_create as FthAction);
+        m.insert("DOES>", w_does as FthAction);
         m.insert("XALLOT", w_allot as FthAction);
         m.insert("BEGIN", w_begin as FthAction);
         m.insert("WHILE", w_while as FthAction);
@@ -100,25 +196,34 @@ lazy_static! {
         m.insert(".\"", w_dot_quote as FthAction);
         m.insert("ABORT\"", w_abort_quote as FthAction);
         m.insert("[']", w_bracket_tick as FthAction);
+        m.insert("LITERAL", w_literal as FthAction);
         m.insert("VERBATIM", w_verbatim as FthAction);
         m.insert("HEADLESSCODE", w_headless as FthAction);
         m.insert("NEXT_IMMEDIATE", w_next_immediate as FthAction);
         m.insert("NEXT_UNLISTED", w_next_unlisted as FthAction);
+        m.insert("NEXT_HOST", w_next_host as FthAction);
         m.insert("[DEFINED]", w_is_defined as FthAction);
         m.insert("[IF]", w_comp_if as FthAction);
         m.insert("[ELSE]", w_comp_else as FthAction);
         m.insert("[THEN]", w_comp_then as FthAction);
         m.insert("INCLUDE", w_include as FthAction);
+        m.insert("REQUIRE", w_require as FthAction);
+        m.insert("EXTERN", w_extern as FthAction);
 
         m
     };
 }
 
 fn w_colon(fth: &mut Fth) -> anyhow::Result<()> {
-    fth.is_compiling = true;
     fth.input_mgr.skip_ws()?;
     let w_to_be_defined = fth.input_mgr.word()?;
-    let w_to_be_defined = w_to_be_defined.expect("EOF after colon!");
+    let w_to_be_defined = w_to_be_defined.context("EOF after colon!")?;
+    if fth.next_is_host {
+        fth.next_is_host = false;
+        return host::define(fth, &w_to_be_defined, Vec::new());
+    }
+    fth.is_compiling = true;
+    fth.open_def = Some((w_to_be_defined.clone(), fth.input_mgr.location()));
     let next_is_immediate = fth.next_is_immediate;
     fth.next_is_immediate = false;
     let next_is_unlisted = fth.next_is_unlisted;
@@ -129,23 +234,50 @@ fn w_colon(fth: &mut Fth) -> anyhow::Result<()> {
 }
 
 fn w_semicolon(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.check_ctrl_closed("';'")?;
     fth.is_compiling = false;
+    fth.open_def = None;
     fth.emit_word("exit");
     fth.close_definition();
 
     Ok(())
 }
 
+fn w_left_bracket(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.is_compiling = false;
+
+    Ok(())
+}
+
+fn w_right_bracket(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.is_compiling = true;
+
+    Ok(())
+}
+
 fn w_code(fth: &mut Fth) -> anyhow::Result<()> {
     fth.input_mgr.skip_ws()?;
     let w_to_be_defined = fth.input_mgr.word()?;
-    let w_to_be_defined = w_to_be_defined.expect("EOF while defining CODE");
+    let w_to_be_defined = w_to_be_defined.context("EOF while defining CODE")?;
     let next_is_immediate = fth.next_is_immediate;
     fth.next_is_immediate = false;
     let next_is_unlisted = fth.next_is_unlisted;
     fth.next_is_unlisted = false;
     fth.create_code(&w_to_be_defined, next_is_immediate, next_is_unlisted);
+    let mid_line = fth.input_mgr.mid_line();
     let mut code_lines = fth.input_mgr.lines_until("END-CODE")?;
+    if let Some((effect, rest)) = code_lines.first().filter(|_| mid_line).and_then(|l| split_effect(l)) {
+        if let Some(effect) = stack_check::parse_effect(&effect) {
+            fth.symbols.set_effect(&w_to_be_defined, fth.search.current(), effect);
+        }
+        if rest.trim().is_empty() {
+            code_lines.remove(0);
+        } else {
+            code_lines[0] = rest;
+        }
+    }
+    fth.awaiting_effect = None;
+    fth.check_ctrl_closed("END-CODE")?;
     code_lines.push("    NEXT\n".to_string());
     fth.emit_lines(code_lines);
     fth.close_definition();
@@ -153,8 +285,55 @@ fn w_code(fth: &mut Fth) -> anyhow::Result<()> {
     Ok(())
 }
 
+/* The stack effect comment that starts the rest of a CODE line, and
+ * what follows it.
+ */
+fn split_effect(line: &str) -> Option<(String, String)> {
+    let text = line.trim_start().strip_prefix('(')?;
+    if !text.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let (effect, rest) = text.split_once(')')?;
+    Some((effect.to_string(), rest.to_string()))
+}
+
+/* EXTERN name declares a word the kernel provides outside of the Forth
+ * source, so that compiling it is not an error.
+ */
+fn w_extern(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.input_mgr.skip_ws()?;
+    let name = fth.input_mgr.word()?;
+    let name = name.context("EOF after EXTERN")?;
+    let loc = fth.input_mgr.location();
+    fth.symbols.define(&name, SymKind::Extern, loc, fth.search.current());
+    fth.awaiting_effect = Some(name);
+
+    Ok(())
+}
+
+/* A comment right after the name of a definition is its stack effect. */
 fn w_paren(fth: &mut Fth) -> anyhow::Result<()> {
-    let _ = fth.input_mgr.str_by(|c: char| c == ')')?;
+    let text = fth.input_mgr.str_by(|c: char| c == ')')?;
+    if let (Some(name), Some(text)) = (fth.awaiting_effect.take(), text) {
+        if let Some(effect) = stack_check::parse_effect(&text) {
+            fth.symbols.set_effect(&name, fth.search.current(), effect);
+        }
+    }
+
+    Ok(())
+}
+
+fn w_backslash(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.input_mgr.skip_line();
+
+    Ok(())
+}
+
+/* Messages from the build itself, e.g. which [IF] branch was taken. */
+fn w_dot_paren(fth: &mut Fth) -> anyhow::Result<()> {
+    let text = fth.input_mgr.str_by(|c: char| c == ')')?;
+    let text = text.context("Unterminated string for '.('")?;
+    eprint!("{text}");
 
     Ok(())
 }
@@ -162,11 +341,11 @@ fn w_paren(fth: &mut Fth) -> anyhow::Result<()> {
 fn w_constant(fth: &mut Fth) -> anyhow::Result<()> {
     fth.input_mgr.skip_ws()?;
     let constant_name = fth.input_mgr.word()?;
-    let constant_name = constant_name.expect("EOF while defining a CONSTANT");
+    let constant_name = constant_name.context("EOF while defining a CONSTANT")?;
     let next_is_unlisted = fth.next_is_unlisted;
     fth.next_is_unlisted = false;
     match fth.data_stack.pop() {
-        None => panic!("Stack underflow for CONSTANT '{constant_name}"),
+        None => anyhow::bail!("Stack underflow for CONSTANT '{constant_name}'"),
         Some(v) => fth.create_constant(&constant_name, v, next_is_unlisted),
     }
 
@@ -176,7 +355,7 @@ fn w_constant(fth: &mut Fth) -> anyhow::Result<()> {
 fn w_variable(fth: &mut Fth) -> anyhow::Result<()> {
     fth.input_mgr.skip_ws()?;
     let variable_name = fth.input_mgr.word()?;
-    let variable_name = variable_name.expect("EOF while defining a VARIABLE");
+    let variable_name = variable_name.context("EOF while defining a VARIABLE")?;
     let next_is_unlisted = fth.next_is_unlisted;
     fth.next_is_unlisted = false;
     fth.create_variable(&variable_name, 1, next_is_unlisted);
@@ -187,7 +366,7 @@ fn w_variable(fth: &mut Fth) -> anyhow::Result<()> {
 fn w_2variable(fth: &mut Fth) -> anyhow::Result<()> {
     fth.input_mgr.skip_ws()?;
     let variable_name = fth.input_mgr.word()?;
-    let variable_name = variable_name.expect("EOF while defining a 2VARIABLE");
+    let variable_name = variable_name.context("EOF while defining a 2VARIABLE")?;
     let next_is_unlisted = fth.next_is_unlisted;
     fth.next_is_unlisted = false;
     fth.create_variable(&variable_name, 2, next_is_unlisted);
@@ -195,10 +374,131 @@ fn w_2variable(fth: &mut Fth) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn w_value(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.input_mgr.skip_ws()?;
+    let value_name = fth.input_mgr.word()?;
+    let value_name = value_name.context("EOF while defining a VALUE")?;
+    let next_is_unlisted = fth.next_is_unlisted;
+    fth.next_is_unlisted = false;
+    match fth.data_stack.pop() {
+        None => anyhow::bail!("Stack underflow for VALUE '{value_name}'"),
+        Some(v) => fth.create_value(&value_name, v, next_is_unlisted),
+    }
+
+    Ok(())
+}
+
+fn w_defer(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.input_mgr.skip_ws()?;
+    let defer_name = fth.input_mgr.word()?;
+    let defer_name = defer_name.context("EOF while defining a DEFER")?;
+    let next_is_unlisted = fth.next_is_unlisted;
+    fth.next_is_unlisted = false;
+    fth.create_defer(&defer_name, next_is_unlisted);
+
+    Ok(())
+}
+
+/* The word after TO or IS, which has to be of `kind`, and the
+ * wordlist it is in.
+ */
+fn store_target(fth: &mut Fth, by: &str, kind: SymKind) -> anyhow::Result<(String, usize)> {
+    fth.input_mgr.skip_ws()?;
+    let name = fth.input_mgr.word()?;
+    let name = name.with_context(|| format!("EOF after {by}"))?;
+    match fth.symbols.find(&name, &fth.search.order()) {
+        None => anyhow::bail!("{by} needs {}, but '{name}' is not defined", kind.describe()),
+        Some(s) if s.kind != kind => anyhow::bail!("{by} needs {}, but '{name}' is {}", kind.describe(), s.kind.describe()),
+        Some(s) => Ok((name, s.wordlist)),
+    }
+}
+
+/* In a definition TO and IS compile a store to the body of the word
+ * that follows.  Outside of one, TO sets the value the image starts
+ * out with and `' word IS name` the word the DEFER starts out with.
+ */
+fn w_to(fth: &mut Fth) -> anyhow::Result<()> {
+    let (name, wordlist) = store_target(fth, "TO", SymKind::Value)?;
+    if fth.is_compiling {
+        fth.store_to_body(&name, wordlist);
+        return Ok(());
+    }
+    match fth.data_stack.pop() {
+        None => anyhow::bail!("Stack underflow for TO '{name}'"),
+        Some(v) => fth.ir.set_value(&name, wordlist, v),
+    }
+
+    Ok(())
+}
+
+fn w_is(fth: &mut Fth) -> anyhow::Result<()> {
+    let (name, wordlist) = store_target(fth, "IS", SymKind::Defer)?;
+    if !fth.is_compiling {
+        let word = fth.ticked.take()
+            .with_context(|| format!("IS outside of a definition needs a ' right before it, as in ' word IS {name}"))?;
+        fth.ir.set_defer(&name, wordlist, word);
+        return Ok(());
+    }
+    fth.store_to_body(&name, wordlist);
+
+    Ok(())
+}
+
+/* Outside a definition ' only feeds IS, which needs the word it read
+ * rather than a number.  Inside one it is compiled like any other word.
+ */
+fn w_tick(fth: &mut Fth) -> anyhow::Result<()> {
+    if fth.is_compiling {
+        fth.use_word("'");
+        return Ok(());
+    }
+    fth.input_mgr.skip_ws()?;
+    let w = fth.input_mgr.word()?;
+    let w = w.context("EOF after '")?;
+    let loc = fth.input_mgr.location();
+    let order = fth.search.order();
+    let wordlist = fth.symbols.find(&w, &order).map_or(FORTH, |s| s.wordlist);
+    fth.symbols.add_use(&w, loc, order);
+    fth.ticked = Some((w, wordlist));
+
+    Ok(())
+}
+
+/* CREATE name starts a data word whose body is whatever `,` and ALLOT
+ * put after it.  First thing in a colon definition, it makes that
+ * definition a defining word instead, which runs inside rfc like a
+ * NEXT_HOST word (see host::define).
+ */
+fn w_create(fth: &mut Fth) -> anyhow::Result<()> {
+    if fth.is_compiling {
+        let name = fth.open_def.as_ref().map(|(n, _)| n.clone()).context("CREATE while compiling outside of a definition")?;
+        if !fth.ir.drop_empty_colon() {
+            anyhow::bail!("CREATE has to come first in the defining word '{name}'");
+        }
+        fth.is_compiling = false;
+        fth.open_def = None;
+        fth.last_is_word = false;
+        fth.symbols.forget(&name, fth.search.current());
+        return host::define(fth, &name, vec![HostOp::Call("CREATE".to_string())]);
+    }
+    fth.input_mgr.skip_ws()?;
+    let name = fth.input_mgr.word()?;
+    let name = name.context("EOF after CREATE")?;
+    let next_is_unlisted = fth.next_is_unlisted;
+    fth.next_is_unlisted = false;
+    fth.create(&name, next_is_unlisted);
+
+    Ok(())
+}
+
+fn w_does(_fth: &mut Fth) -> anyhow::Result<()> {
+    anyhow::bail!("DOES> outside of a defining word");
+}
+
 fn w_allot(fth: &mut Fth) -> anyhow::Result<()> {
     match fth.data_stack.pop() {
-        None => panic!("Stack underflow for ALLOT"),
-        Some(v) => fth.allot_space(v.try_into().expect("Bad numerical format for u64")),
+        None => anyhow::bail!("Stack underflow for ALLOT"),
+        Some(v) => fth.allot_space(v.try_into().context("Bad numerical format for u64")?),
     }
 
     Ok(())
@@ -207,27 +507,27 @@ fn w_allot(fth: &mut Fth) -> anyhow::Result<()> {
 fn w_begin(fth: &mut Fth) -> anyhow::Result<()> {
     let lab_begin = fth.new_label();
     fth.emit_label(&lab_begin);
-    fth.ctrl_other_stack.push(lab_begin);
+    fth.push_ctrl(Ctrl::Begin(lab_begin));
 
     Ok(())
 }
 
 fn w_while(fth: &mut Fth) -> anyhow::Result<()> {
-    let lab_begin = fth.ctrl_other_stack.pop().expect("Missing BEGIN label at WHILE");
+    let begin = fth.pop_ctrl("WHILE", &[CtrlKind::Begin])?;
     let lab_end = fth.new_label();
 
     fth.emit_word("qbranch");
     fth.refer_to_label(&lab_end);
 
-    fth.ctrl_other_stack.push(lab_end);
-    fth.ctrl_other_stack.push(lab_begin);
+    fth.push_ctrl(Ctrl::While(lab_end));
+    fth.ctrl_stack.push(begin);
 
     Ok(())
 }
 
 fn w_repeat(fth: &mut Fth) -> anyhow::Result<()> {
-    let lab_begin = fth.ctrl_other_stack.pop().expect("Missing BEGIN label at REPEAT");
-    let lab_end = fth.ctrl_other_stack.pop().expect("Missing WHILE label at REPEAT");
+    let lab_begin = fth.pop_ctrl("REPEAT", &[CtrlKind::Begin])?.ctrl.label();
+    let lab_end = fth.pop_ctrl("REPEAT", &[CtrlKind::While])?.ctrl.label();
 
     fth.emit_word("branch");
     fth.refer_to_label(&lab_begin);
@@ -237,7 +537,7 @@ fn w_repeat(fth: &mut Fth) -> anyhow::Result<()> {
 }
 
 fn w_until(fth: &mut Fth) -> anyhow::Result<()> {
-    let lab_begin = fth.ctrl_other_stack.pop().expect("Missing BEGIN label at UNTIL");
+    let lab_begin = fth.pop_ctrl("UNTIL", &[CtrlKind::Begin])?.ctrl.label();
 
     fth.emit_word("qbranch");
     fth.refer_to_label(&lab_begin);
@@ -246,7 +546,7 @@ fn w_until(fth: &mut Fth) -> anyhow::Result<()> {
 }
 
 fn w_again(fth: &mut Fth) -> anyhow::Result<()> {
-    let lab_begin = fth.ctrl_other_stack.pop().expect("Missing BEGIN label at AGAIN");
+    let lab_begin = fth.pop_ctrl("AGAIN", &[CtrlKind::Begin])?.ctrl.label();
 
     fth.emit_word("branch");
     fth.refer_to_label(&lab_begin);
@@ -258,24 +558,24 @@ fn w_if(fth: &mut Fth) -> anyhow::Result<()> {
     let label = fth.new_label();
     fth.emit_word("qbranch");
     fth.refer_to_label(&label);
-    fth.ctrl_other_stack.push(label);
+    fth.push_ctrl(Ctrl::If(label));
 
     Ok(())
 }
 
 fn w_else(fth: &mut Fth) -> anyhow::Result<()> {
-    let head_label = fth.ctrl_other_stack.pop().expect("Missing IF for ELSE");
+    let head_label = fth.pop_ctrl("ELSE", &[CtrlKind::If])?.ctrl.label();
     let else_label = fth.new_label();
     fth.emit_word("branch");
     fth.refer_to_label(&else_label);
-    fth.ctrl_other_stack.push(else_label);
+    fth.push_ctrl(Ctrl::Else(else_label));
     fth.emit_label(&head_label);
 
     Ok(())
 }
 
 fn w_then(fth: &mut Fth) -> anyhow::Result<()> {
-    let label = fth.ctrl_other_stack.pop().expect("Missing IF/ELSE for THEN");
+    let label = fth.pop_ctrl("THEN", &[CtrlKind::If, CtrlKind::Else, CtrlKind::While])?.ctrl.label();
     fth.emit_label(&label);
 
     Ok(())
@@ -286,8 +586,7 @@ fn w_do(fth: &mut Fth) -> anyhow::Result<()> {
     let forward = fth.new_label();
     fth.emit_word("2to_r");
     fth.emit_label(&backward);
-    fth.ctrl_do_stack.push(backward);
-    fth.ctrl_do_stack.push(forward);
+    fth.push_ctrl(Ctrl::Do { backward, forward });
 
     Ok(())
 }
@@ -301,14 +600,18 @@ fn w_question_do(fth: &mut Fth) -> anyhow::Result<()> {
     fth.emit_word("qbranch");
     fth.refer_to_label(&forward);
     fth.emit_label(&backward);
-    fth.ctrl_do_stack.push(backward);
-    fth.ctrl_do_stack.push(forward);
+    fth.push_ctrl(Ctrl::Do { backward, forward });
 
     Ok(())
 }
 
 fn w_leave(fth: &mut Fth) -> anyhow::Result<()> {
-    let label = fth.ctrl_do_stack.last().expect("Missing DO for LOOP").clone();
+    let label = fth.ctrl_stack.iter().rev()
+        .find_map(|e| match &e.ctrl {
+            Ctrl::Do { forward, .. } => Some(forward.clone()),
+            _ => None,
+        })
+        .context("LEAVE outside of DO ... LOOP")?;
     fth.emit_word("branch");
     fth.refer_to_label(&label);
 
@@ -316,8 +619,9 @@ fn w_leave(fth: &mut Fth) -> anyhow::Result<()> {
 }
 
 fn  w_loop(fth: &mut Fth) -> anyhow::Result<()> {
-    let forward = fth.ctrl_do_stack.pop().expect("Missing DO for LOOP");
-    let backward = fth.ctrl_do_stack.pop().expect("Missing DO for LOOP");
+    let Ctrl::Do { backward, forward } = fth.pop_ctrl("LOOP", &[CtrlKind::Do])?.ctrl else {
+        unreachable!();
+    };
     fth.emit_word("do_loop1");
     fth.refer_to_label(&backward);
     fth.emit_label(&forward);
@@ -327,8 +631,9 @@ fn  w_loop(fth: &mut Fth) -> anyhow::Result<()> {
 }
 
 fn  w_plus_loop(fth: &mut Fth) -> anyhow::Result<()> {
-    let forward = fth.ctrl_do_stack.pop().expect("Missing DO for LOOP");
-    let backward = fth.ctrl_do_stack.pop().expect("Missing DO for LOOP");
+    let Ctrl::Do { backward, forward } = fth.pop_ctrl("+LOOP", &[CtrlKind::Do])?.ctrl else {
+        unreachable!();
+    };
     fth.emit_word("do_plus_loop1");
     fth.refer_to_label(&backward);
     fth.emit_label(&forward);
@@ -351,20 +656,24 @@ fn w_headless(fth: &mut Fth) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn w_immediate(_fth: &mut Fth) -> anyhow::Result<()> {
-    panic!("FIXME: make a decision about word caching or not, please.");
-
-    //Ok(())
+fn w_immediate(fth: &mut Fth) -> anyhow::Result<()> {
+    if !fth.last_is_word {
+        anyhow::bail!("IMMEDIATE must follow a colon or CODE definition");
+    }
+    fth.ir.make_immediate()
 }
 
 fn w_case(fth: &mut Fth) -> anyhow::Result<()> {
     let label = fth.new_label();
-    fth.ctrl_other_stack.push(label);
+    fth.push_ctrl(Ctrl::Case(label));
 
     Ok(())
 }
 
 fn w_of(fth: &mut Fth) -> anyhow::Result<()> {
+    if !matches!(fth.ctrl_stack.last(), Some(CtrlEntry { ctrl: Ctrl::Case(_), .. })) {
+        anyhow::bail!("OF without CASE");
+    }
     let lab_skip = fth.new_label();
     fth.emit_word("over");
     fth.emit_word("equals");
@@ -372,14 +681,16 @@ fn w_of(fth: &mut Fth) -> anyhow::Result<()> {
     fth.refer_to_label(&lab_skip);
     fth.emit_word("drop");
 
-    fth.ctrl_other_stack.push(lab_skip);
+    fth.push_ctrl(Ctrl::Of(lab_skip));
     Ok(())
 }
 
 fn w_endof(fth: &mut Fth) -> anyhow::Result<()> {
-    let lab_skip = fth.ctrl_other_stack.pop().expect("Missing OF for ENDOF");
-    let lab_end = fth.ctrl_other_stack.last().expect("Missing CASE for ENDOF");
-    let lab_end = lab_end.clone();
+    let lab_skip = fth.pop_ctrl("ENDOF", &[CtrlKind::Of])?.ctrl.label();
+    let lab_end = match fth.ctrl_stack.last() {
+        Some(CtrlEntry { ctrl: Ctrl::Case(l), .. }) => l.clone(),
+        _ => anyhow::bail!("ENDOF without CASE"),
+    };
 
     fth.emit_word("branch");
     fth.refer_to_label(&lab_end);
@@ -389,7 +700,7 @@ fn w_endof(fth: &mut Fth) -> anyhow::Result<()> {
 }
 
 fn w_endcase(fth: &mut Fth) -> anyhow::Result<()> {
-    let lab_end = fth.ctrl_other_stack.pop().expect("Missing ENDOF for ENDCASE");
+    let lab_end = fth.pop_ctrl("ENDCASE", &[CtrlKind::Case])?.ctrl.label();
     fth.emit_word("drop");
     fth.emit_label(&lab_end);
 
@@ -399,7 +710,8 @@ fn w_endcase(fth: &mut Fth) -> anyhow::Result<()> {
 fn w_s_quote(fth: &mut Fth) -> anyhow::Result<()> {
     fth.input_mgr.skip_ws()?;
     let term_str = fth.input_mgr.str_by(|c: char| c == '"')?;
-    let term_str = term_str.expect("Unterminated string for 's\"'");
+    let term_str = term_str.context("Unterminated string for 's\"'")?;
+    let term_str = fth.input_mgr.text(term_str)?;
     let branch_target = fth.new_label();
     let string_loc = fth.new_label();
     fth.emit_word("branch");
@@ -417,7 +729,8 @@ fn w_s_quote(fth: &mut Fth) -> anyhow::Result<()> {
 fn w_dot_quote(fth: &mut Fth) -> anyhow::Result<()> {
     fth.input_mgr.skip_ws()?;
     let term_str = fth.input_mgr.str_by(|c: char| c == '"')?;
-    let term_str = term_str.expect("Unterminated string for '.\"'");
+    let term_str = term_str.context("Unterminated string for '.\"'")?;
+    let term_str = fth.input_mgr.text(term_str)?;
     let branch_target = fth.new_label();
     let string_loc = fth.new_label();
     fth.emit_word("branch");
@@ -436,7 +749,8 @@ fn w_dot_quote(fth: &mut Fth) -> anyhow::Result<()> {
 fn w_abort_quote(fth: &mut Fth) -> anyhow::Result<()> {
     fth.input_mgr.skip_ws()?;
     let term_str = fth.input_mgr.str_by(|c: char| c == '"')?;
-    let term_str = term_str.expect("Unterminated string for 'abort\"'");
+    let term_str = term_str.context("Unterminated string for 'abort\"'")?;
+    let term_str = fth.input_mgr.text(term_str)?;
     let cont_target = fth.new_label();
     let abort_target = fth.new_label();
     let string_loc = fth.new_label();
@@ -461,9 +775,18 @@ fn w_abort_quote(fth: &mut Fth) -> anyhow::Result<()> {
 fn w_bracket_tick(fth: &mut Fth) -> anyhow::Result<()> {
     fth.input_mgr.skip_ws()?;
     let w = fth.input_mgr.word()?;
-    let w = w.expect("EOF in '[']'");
+    let w = w.context("EOF in '[']'")?;
     fth.emit_word("lit");
-    fth.emit_word(&w);
+    fth.use_word(&w);
+
+    Ok(())
+}
+
+fn w_literal(fth: &mut Fth) -> anyhow::Result<()> {
+    match fth.data_stack.pop() {
+        None => anyhow::bail!("Stack underflow for LITERAL"),
+        Some(v) => fth.do_literal(v),
+    }
 
     Ok(())
 }
@@ -480,10 +803,16 @@ fn w_next_unlisted(fth: &mut Fth) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn w_next_host(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.next_is_host = true;
+
+    Ok(())
+}
+
 fn w_is_defined(fth: &mut Fth) -> anyhow::Result<()> {
     fth.input_mgr.skip_ws()?;
     let def_name = fth.input_mgr.word()?;
-    let def_name = def_name.expect("EOF after [defined]!");
+    let def_name = def_name.context("EOF after [defined]!")?;
     if fth.defines.contains(&def_name) {
         fth.data_stack.push(-1);
     } else {
@@ -499,36 +828,55 @@ fn w_is_defined(fth: &mut Fth) -> anyhow::Result<()> {
  */
 fn w_comp_if(fth: &mut Fth) -> anyhow::Result<()> {
     let should_compile = match fth.data_stack.pop() {
-        None => panic!("Stack underflow for [IF]"),
+        None => anyhow::bail!("Stack underflow for [IF]"),
         Some(v) => v != 0
     };
 
+    let loc = fth.input_mgr.location();
     if should_compile {
-        fth.skip_stack.push(CondCompileState::CompileUntilElse);
+        fth.skip_stack.push((CondCompileState::CompileUntilElse, loc));
     } else {
-        fth.skip_stack.push(CondCompileState::SkipUntilElse);
+        fth.skip_stack.push((CondCompileState::SkipUntilElse, loc));
     }
     Ok(())
 }
 
 fn w_comp_else(_fth: &mut Fth) -> anyhow::Result<()> {
-
-    panic!("*** Internal error: [ELSE] action reached!");
+    anyhow::bail!("*** Internal error: [ELSE] action reached!");
 }
 
 fn w_comp_then(_fth: &mut Fth) -> anyhow::Result<()> {
-    panic!("*** Internal error: [THEN] action reached!");
+    anyhow::bail!("*** Internal error: [THEN] action reached!");
 }
 
 fn w_include(fth: &mut Fth) -> anyhow::Result<()> {
     fth.input_mgr.skip_ws()?;
-    let file_name = fth.input_mgr.word()?;
-    let file_name = file_name.expect("EOF after include!");
-    fth.input_mgr.open_file(&file_name)?;
+    let file_name = fth.input_mgr.raw_word()?;
+    let file_name = file_name.context("EOF after include!")?;
+    fth.input_mgr.include(&file_name, false)?;
+
+    Ok(())
+}
+
+fn w_require(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.input_mgr.skip_ws()?;
+    let file_name = fth.input_mgr.raw_word()?;
+    let file_name = file_name.context("EOF after REQUIRE")?;
+    fth.input_mgr.include(&file_name, true)?;
 
     Ok(())
 }
 
+fn parse_number(w: &str) -> anyhow::Result<Option<i64>> {
+    if let Some(hex) = w.strip_prefix("0x") {
+        Ok(Some(i64::from_str_radix(hex, 16)?))
+    } else if let Some(bin) = w.strip_prefix("0b") {
+        Ok(Some(i64::from_str_radix(bin, 2)?))
+    } else {
+        Ok(w.parse::<i64>().ok())
+    }
+}
+
 fn word_to_symbol(word_string: &str) -> String {
     let mut result = String::from("w_");
     let mut needs_underscore = false;
@@ -539,6 +887,10 @@ fn word_to_symbol(word_string: &str) -> String {
             needs_underscore = false;
         }
         match SYMLINKAGE.get(&c) {
+            None if !c.is_ascii() => {
+                result.push_str(&format!("u{:04x}", c as u32));
+                needs_underscore = true;
+            }
             None => {
                 result.push(c);
             }
@@ -551,16 +903,39 @@ fn word_to_symbol(word_string: &str) -> String {
     result
 }
 
+/* The label of a word: word_to_symbol() for FORTH, and the number of
+ * the wordlist after the `w` for any other, `w2_foo` for instance, so
+ * that each wordlist can have a `foo` of its own.
+ */
+fn word_symbol(wordlist: usize, name: &str) -> String {
+    let sym = word_to_symbol(name);
+    if wordlist == wordlists::FORTH {
+        return sym;
+    }
+    format!("w{wordlist}{}", &sym[1..])
+}
+
 enum EscapeMethod {
     Backslash,
     Double,
 }
 
+/* GNU as takes octal escapes, so with Backslash non-ASCII chars are
+ * written as their UTF-8 bytes and a backslash is doubled.  64tass
+ * strings have no escapes; see tass_text() for those.
+ */
 fn escape_quotes(method: EscapeMethod, w: &str) -> String {
     let mut result = String::new();
 
     for c in w.chars() {
-        if c == '"' {
+        if !c.is_ascii() && matches!(method, EscapeMethod::Backslash) {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                result.push_str(&format!("\\{b:03o}"));
+            }
+        } else if c == '\\' && matches!(method, EscapeMethod::Backslash) {
+            result.push_str("\\\\");
+        } else if c == '"' {
             match method {
                 EscapeMethod::Backslash => result.push('\\'),
                 EscapeMethod::Double => result.push('"'),
@@ -573,35 +948,168 @@ fn escape_quotes(method: EscapeMethod, w: &str) -> String {
     result
 }
 
+/* A 64tass `.text` operand: quoted runs of ASCII, with the UTF-8 bytes
+ * of anything else as separate numbers.
+ */
+fn tass_text(s: &str) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut run = String::new();
+
+    for b in s.bytes() {
+        if b.is_ascii() {
+            run.push(char::from(b));
+        } else {
+            if !run.is_empty() {
+                parts.push(format!("\"{}\"", escape_quotes(EscapeMethod::Double, &run)));
+                run.clear();
+            }
+            parts.push(format!("${b:02x}"));
+        }
+    }
+    if !run.is_empty() || parts.is_empty() {
+        parts.push(format!("\"{}\"", escape_quotes(EscapeMethod::Double, &run)));
+    }
+    parts.join(", ")
+}
+
+/* Words come with `sym`, their label from word_symbol(), next to the
+ * name that goes into the header.
+ */
 pub trait FthGen {
     fn prolog(&mut self);
     fn do_literal(&mut self, n: i64);
     fn do_string_literal(&mut self, s: &str);
-    fn create_word(&mut self, w: &str, is_immediate: bool, unlisted: bool);
-    fn create_code(&mut self, w: &str, is_immediate: bool, unlisted: bool);
+    fn create_word(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool);
+    fn create_code(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool);
     fn close_definition(&mut self);
-    fn emit_word(&mut self, w: &str);
+    fn emit_word(&mut self, w: &str, sym: &str);
+    fn emit_cell(&mut self, n: i64);
     fn emit_lines(&mut self, lines: Vec<String>);
     fn refer_to_label(&mut self, w: &str);
     fn emit_label(&mut self, l: &str);
-    fn create_constant(&mut self, name: &str, val: i64, unlisted: bool);
-    fn create_variable(&mut self, name: &str, size: u8, unlisted: bool);
+    fn create_constant(&mut self, name: &str, sym: &str, val: i64, unlisted: bool);
+    fn create_variable(&mut self, name: &str, sym: &str, size: u8, unlisted: bool);
+    /* VALUE and DEFER words hold one cell, a number or an execution
+     * token, that TO and IS store to through refer_to_body().  A DEFER
+     * starts out with the xt of `action`, a label, or 0.
+     */
+    fn create_value(&mut self, name: &str, sym: &str, val: i64, unlisted: bool);
+    fn create_defer(&mut self, name: &str, sym: &str, action: Option<&str>, unlisted: bool);
+    fn refer_to_body(&mut self, sym: &str);
+    /* A CREATE word after DOES>: no cells of its own, and `action`, the
+     * label of a start_does(), as its action.
+     */
+    fn create_with_action(&mut self, name: &str, sym: &str, action: &str, unlisted: bool);
+    /* The DOES> part of a defining word: `label`, a code field that
+     * calls the kernel's w_dodoes, and the thread up to the next
+     * close_definition().
+     */
+    fn start_does(&mut self, label: &str);
     fn allot_space(&mut self, size: u64);
-    fn epilog(&mut self);
+    /* Link the headers that follow into the chain ending in `head`. */
+    fn set_wordlist(&mut self, head: &str);
+    fn epilog(&mut self) -> anyhow::Result<()>;
+    /* The --token-map text, once epilog() has run. */
+    fn token_map(&self) -> Option<String> {
+        None
+    }
+}
+
+/* The dictionary link chains of a generator, one per wordlist, in the
+ * order they were first used; `dict_head` comes first.  The chain being
+ * added to lives in the generator's last_dict_entry, the others wait
+ * here.
+ */
+pub struct Chains<T> {
+    heads: Vec<(String, T)>,
+    cur: usize,
+    empty: T,
+}
+
+impl<T: Clone> Chains<T> {
+    pub fn new(empty: T) -> Self {
+        Chains {
+            heads: vec![(String::from("dict_head"), empty.clone())],
+            cur: 0,
+            empty,
+        }
+    }
+
+    pub fn switch(&mut self, head: &str, last: &mut T) {
+        self.heads[self.cur].1 = last.clone();
+        self.cur = match self.heads.iter().position(|(h, _)| h == head) {
+            Some(i) => i,
+            None => {
+                self.heads.push((head.to_string(), self.empty.clone()));
+                self.heads.len() - 1
+            }
+        };
+        *last = self.heads[self.cur].1.clone();
+    }
+
+    /* Every chain with the last entry on it. */
+    pub fn all(&mut self, last: &T) -> Vec<(String, T)> {
+        self.heads[self.cur].1 = last.clone();
+        self.heads.clone()
+    }
 }
 
+/* With `--threading dtc` every header is a CODE_W, so the code field is
+ * machine code, and high-level words, constants and variables start
+ * with a `call` to their action, which finds the body as the return
+ * address.
+ */
 struct AttGen {
     _is_compiling: bool,
     last_dict_entry: String,
+    chains: Chains<String>,
+    wide: bool,
+    threading: Threading,
+    out: OutBuf,
 }
 
 impl AttGen {
-    fn new() -> Self {
+    fn new(out: Box<dyn Write>, wide: bool, threading: Threading) -> Self {
         AttGen {
             _is_compiling: false,
             last_dict_entry: String::from("0"),
+            chains: Chains::new(String::from("0")),
+            wide,
+            threading,
+            out: OutBuf::new(out),
+        }
+    }
+
+    fn data_header(&mut self, name: &str, name_sym: &str, action: &str) {
+        let name_len = name.len();
+        let name = escape_quotes(EscapeMethod::Backslash, name);
+        if self.threading == Threading::Dtc {
+            self.out.header(format!("    CODE_W {name_sym} {name_len} \"{name}\""));
+            self.out.line(format!("    call {action}"));
+        } else {
+            self.out.header(format!("    HIGH_W {name_sym} {name_len} \"{name}\" act={action}"));
+        }
+    }
+
+    fn cell_dir(&self) -> &'static str {
+        if self.wide { ".quad" } else { ".int" }
+    }
+
+    fn cell(&self, n: i64) -> String {
+        if self.wide {
+            format!("    .quad {n}")
+        } else {
+            let l = n as i32;
+            format!("    .int {l}")
         }
     }
+
+    fn word_header(kind: &str, w: &str, word_sym: &str, is_immediate: bool) -> String {
+        let word_len = w.len();
+        let w = escape_quotes(EscapeMethod::Backslash, w);
+        let flags:u8 = if is_immediate { 1 } else { 0 };
+        format!("    {kind} {word_sym} {word_len} \"{w}\" flgs={flags}")
+    }
 }
 
 impl FthGen for AttGen {
@@ -609,229 +1117,538 @@ impl FthGen for AttGen {
     }
 
     fn do_literal(&mut self, n: i64) {
-        println!("    .int w_lit");
-        let l = n as i32;
-        println!("    .int {l}");
+        let dir = self.cell_dir();
+        self.out.line(format!("    {dir} w_lit"));
+        self.out.line(self.cell(n));
     }
 
     fn do_string_literal(&mut self, s: &str) {
-        println!("    .ascii \"{s}\"");
+        let s = escape_quotes(EscapeMethod::Backslash, s);
+        self.out.line(format!("    .ascii \"{s}\""));
+        if self.wide {
+            self.out.line("    .balign 8");
+        }
     }
 
-    fn create_word(&mut self, w: &str, is_immediate: bool, unlisted: bool) {
-        let word_sym = word_to_symbol(&w);
-        let word_len = w.len();
-        let w = escape_quotes(EscapeMethod::Backslash, w);
-        let flags:u8 = if is_immediate { 1 } else { 0 };
-        println!("    HIGH_W {word_sym} {word_len} \"{w}\" flgs={flags}");
+    fn create_word(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        if self.threading == Threading::Dtc {
+            self.out.header(AttGen::word_header("CODE_W", w, sym, is_immediate));
+            self.out.line("    call w_docol");
+        } else {
+            self.out.header(AttGen::word_header("HIGH_W", w, sym, is_immediate));
+        }
         if !unlisted {
-            self.last_dict_entry = word_sym.clone();
+            self.last_dict_entry = sym.to_string();
         }
     }
 
-    fn create_code(&mut self, w: &str, is_immediate: bool, unlisted: bool) {
-        let word_sym = word_to_symbol(&w);
-        let word_len = w.len();
-        let w = escape_quotes(EscapeMethod::Backslash, w);
-        let flags:u8 = if is_immediate { 1 } else { 0 };
-        println!("    CODE_W {word_sym} {word_len} \"{w}\" flgs={flags}");
+    fn create_code(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        self.out.header(AttGen::word_header("CODE_W", w, sym, is_immediate));
         if !unlisted {
-            self.last_dict_entry = word_sym.clone();
+            self.last_dict_entry = sym.to_string();
         }
     }
 
+
     fn close_definition(&mut self) {
     }
 
-    fn emit_word(&mut self, w: &str) {
-        let word_sym = word_to_symbol(&w);
-        println!("    .int {word_sym}");
+    fn emit_word(&mut self, _w: &str, sym: &str) {
+        let dir = self.cell_dir();
+        self.out.line(format!("    {dir} {sym}"));
+    }
+
+    fn emit_cell(&mut self, n: i64) {
+        self.out.line(self.cell(n));
     }
 
     fn emit_lines(&mut self, lines: Vec<String>) {
         for l in lines {
-            print!("{l}");
+            self.out.text(l);
         }
     }
 
     fn refer_to_label(&mut self, w: &str) {
-        println!("    .int {w}");
+        let dir = self.cell_dir();
+        self.out.line(format!("    {dir} {w}"));
     }
 
     fn emit_label(&mut self, l: &str) {
-        println!("{l}:");
+        self.out.line(format!("{l}:"));
     }
 
-    fn create_constant(&mut self, name: &str, val: i64, unlisted: bool) {
-        let name_sym = word_to_symbol(&name);
-        let name_len = name.len();
-        let name = escape_quotes(EscapeMethod::Backslash, name);
-        let const_val = val as i32;
-        println!("    HIGH_W {name_sym} {name_len} \"{name}\" act=w_do_const");
-        println!("    .int {const_val}");
+    fn create_constant(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        self.data_header(name, sym, "w_do_const");
+        self.out.line(self.cell(val));
         if !unlisted {
-            self.last_dict_entry = name_sym.clone();
+            self.last_dict_entry = sym.to_string();
         }
     }
 
-    fn create_variable(&mut self, name: &str, size: u8, unlisted: bool) {
-        let name_sym = word_to_symbol(&name);
-        let name_len = name.len();
-        let name = escape_quotes(EscapeMethod::Backslash, name);
-        println!("    HIGH_W {name_sym} {name_len} \"{name}\" act=w_do_var");
+    fn create_variable(&mut self, name: &str, sym: &str, size: u8, unlisted: bool) {
+        self.data_header(name, sym, "w_do_var");
         for _ in 0..size {
-            println!("    .int 0");
+            self.out.line(self.cell(0));
+        }
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
         }
+    }
+
+    fn set_wordlist(&mut self, head: &str) {
+        self.chains.switch(head, &mut self.last_dict_entry);
+    }
+
+    fn create_value(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        self.data_header(name, sym, "w_do_const");
+        self.out.line(format!("{sym}_body:"));
+        self.out.line(self.cell(val));
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn create_defer(&mut self, name: &str, sym: &str, action: Option<&str>, unlisted: bool) {
+        self.data_header(name, sym, "w_do_defer");
+        self.out.line(format!("{sym}_body:"));
+        match action {
+            Some(a) => self.out.line(format!("    {} {a}", self.cell_dir())),
+            None => self.out.line(self.cell(0)),
+        }
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn refer_to_body(&mut self, sym: &str) {
+        let dir = self.cell_dir();
+        self.out.line(format!("    {dir} {sym}_body"));
+    }
+
+    fn create_with_action(&mut self, name: &str, sym: &str, action: &str, unlisted: bool) {
+        self.data_header(name, sym, action);
         if !unlisted {
-            self.last_dict_entry = name_sym.clone();
+            self.last_dict_entry = sym.to_string();
         }
     }
 
+    fn start_does(&mut self, label: &str) {
+        self.out.line(format!("{label}:"));
+        self.out.line("    call w_dodoes");
+    }
+
     fn allot_space(&mut self, size: u64) {
-        println!("    .space {size}");
+        self.out.line(format!("    .space {size}"));
+        if self.wide {
+            self.out.line("    .balign 8");
+        }
     }
 
-    fn epilog(&mut self) {
-        let de = &self.last_dict_entry;
-        println!("dict_head: .int dict_{de}");
+    fn epilog(&mut self) -> anyhow::Result<()> {
+        let dir = self.cell_dir();
+        for (head, de) in self.chains.all(&self.last_dict_entry) {
+            self.out.line(format!("{head}: {dir} dict_{de}"));
+        }
+        self.out.finish()?;
+
+        Ok(())
     }
 }
 
+/* With `--threading dtc` colon definitions, constants and variables use
+ * .CODE_W headers and a `JSR` to their action as the code field; the
+ * action pulls the body address off the return stack.
+ *
+ * With `--threading stc` a colon body is 6502 code instead of a list of
+ * cells: one `JSR` per word and `RTS` for EXIT.  The kernel's .HIGH_W
+ * is expected to leave the code field executable.  The data stack is
+ * the usual zero-page stack indexed by X (TOS in 0,x/1,x), so literals
+ * are pushed inline and BRANCH/QBRANCH become JMP and a test of TOS.
+ * Those words are held in `pending` until the label or word that
+ * follows them arrives.  Anything else that takes an inline cell
+ * (do_loop1, ...) still gets it right after the JSR, where the kernel
+ * can find it through the return address.
+ *
+ * With `--threading token` a colon body is a `.byte` stream.  Every word
+ * that gets compiled is given a token in order of first use; tokens
+ * below TOKEN_ESCAPE are one byte, the rest are an escape byte
+ * (TOKEN_ESCAPE + page) followed by the index within that page.
+ * Inline operands stay full cells.  The dispatch table `token_table`
+ * holds the code field of every token in order and is written out by
+ * epilog(), along with the map file if one was asked for.
+ */
+const TOKEN_ESCAPE: usize = 0xf0;
+const MAX_TOKENS: usize = TOKEN_ESCAPE + (0x100 - TOKEN_ESCAPE) * 0x100;
+
 struct Ca6502 {
     _is_compiling: bool,
     last_dict_entry: String,
+    chains: Chains<String>,
+    threading: Threading,
+    pending: Option<String>,
+    tokens: Vec<(String, String)>,
+    token_ids: HashMap<String, usize>,
+    operand_next: bool,
+    token_map: Option<String>,
+    out: OutBuf,
 }
 
 impl Ca6502 {
-    fn new() -> Self {
+    fn new(out: Box<dyn Write>, threading: Threading) -> Self {
         Ca6502 {
             _is_compiling: false,
             last_dict_entry: String::from("0"),
+            chains: Chains::new(String::from("0")),
+            threading,
+            pending: None,
+            tokens: Vec::new(),
+            token_ids: HashMap::new(),
+            operand_next: false,
+            token_map: None,
+            out: OutBuf::new(out),
         }
     }
-}
 
-fn ref_str(s: &str) -> String {
-    if s.len() == 1 {
-        let first_char = s.chars().next().unwrap();
-        if first_char == '0' {
-            return s.to_string();
+    fn token_bytes(t: usize) -> String {
+        if t < TOKEN_ESCAPE {
+            format!("${t:02x}")
+        } else {
+            let t = t - TOKEN_ESCAPE;
+            format!("${:02x}, ${:02x}", TOKEN_ESCAPE + t / 0x100, t % 0x100)
         }
     }
-    let s2 = format!("{}.cfa", s);
-    s2
-}
 
-impl FthGen for Ca6502 {
-    fn prolog(&mut self) {
+    fn token_for(&mut self, w: &str, word_sym: String) -> usize {
+        if let Some(t) = self.token_ids.get(&word_sym) {
+            return *t;
+        }
+        let t = self.tokens.len();
+        self.token_ids.insert(word_sym.clone(), t);
+        self.tokens.push((word_sym, w.to_string()));
+        t
     }
 
-    fn do_literal(&mut self, n: i64) {
-        println!("    .word w_lit.cfa");
-        let l = n as i16;
-        if l < 0 {
-            println!("    .sint {l}");
-        } else {
-            println!("    .word {l}");
+    fn write_tokens(&mut self) -> anyhow::Result<()> {
+        if self.tokens.len() > MAX_TOKENS {
+            anyhow::bail!("{} words need tokens but at most {MAX_TOKENS} are available", self.tokens.len());
         }
+        self.out.line(format!("TOKEN_ESCAPE = ${TOKEN_ESCAPE:02x}"));
+        self.out.line("token_table");
+        for (sym, _) in &self.tokens {
+            self.out.line(format!("    .addr {}", ref_str(sym)));
+        }
+        let mut map = String::new();
+        for (t, (sym, name)) in self.tokens.iter().enumerate() {
+            map.push_str(&format!("{t}\t{}\t{sym}\t{name}\n", Ca6502::token_bytes(t)));
+        }
+        self.token_map = Some(map);
+        Ok(())
     }
 
-    fn do_string_literal(&mut self, s: &str) {
-        println!("    .text \"{s}\"");
+    fn push_value(&mut self, lo: &str, hi: &str) {
+        self.out.line("    DEX");
+        self.out.line("    DEX");
+        self.out.line(format!("    LDA #{lo}"));
+        self.out.line("    STA 0,x");
+        self.out.line(format!("    LDA #{hi}"));
+        self.out.line("    STA 1,x");
     }
 
-    fn create_word(&mut self, w: &str, is_immediate: bool, unlisted: bool) {
-        let word_sym = word_to_symbol(&w);
-        let word_len = w.len();
-        let mut w = escape_quotes(EscapeMethod::Double, w);
-        let flags:u8 = if is_immediate { 1 } else { 0 };
-        w.make_ascii_uppercase();
-        let last_ref = ref_str(&self.last_dict_entry);
-        println!("{word_sym}    .HIGH_W {word_len}, \"{w}\", , {flags}, {last_ref}");
-        println!("  .block");
-        if !unlisted {
-            self.last_dict_entry = word_sym.clone();
+    /* A word that was held back for its operand but got none. */
+    fn flush_pending(&mut self) {
+        if let Some(sym) = self.pending.take() {
+            self.out.line(format!("    JSR {}", ref_str(&sym)));
         }
     }
 
-    fn create_code(&mut self, w: &str, is_immediate: bool, unlisted: bool) {
-        let word_sym = word_to_symbol(&w);
+    fn stc_operand(&mut self, target: &str) -> bool {
+        match self.pending.take().as_deref() {
+            Some("w_lit") => {
+                self.push_value(&format!("<{target}"), &format!(">{target}"));
+            }
+            Some("w_branch") => {
+                self.out.line(format!("    JMP {target}"));
+            }
+            Some("w_qbranch") => {
+                self.out.line("    LDA 0,x");
+                self.out.line("    ORA 1,x");
+                self.out.line("    INX");
+                self.out.line("    INX");
+                self.out.line("    CMP #0");
+                self.out.line("    BNE +");
+                self.out.line(format!("    JMP {target}"));
+                self.out.line("+");
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /* Header names are macro arguments, which can only be one quoted
+     * string, so Fth::interpret() has made sure they are ASCII.
+     */
+    fn word_header(kind: &str, w: &str, word_sym: &str, is_immediate: bool, last_ref: &str) -> String {
         let word_len = w.len();
         let mut w = escape_quotes(EscapeMethod::Double, w);
         let flags:u8 = if is_immediate { 1 } else { 0 };
         w.make_ascii_uppercase();
+        match kind {
+            "CODE_W" => format!("{word_sym}    .CODE_W {word_len}, \"{w}\", {flags}, {last_ref}"),
+            _ => format!("{word_sym}    .{kind} {word_len}, \"{w}\", , {flags}, {last_ref}"),
+        }
+    }
+
+    fn data_header(name: &str, name_sym: &str, action: &str, threading: Threading, last_ref: &str) -> String {
+        let name_len = name.len();
+        let mut name = escape_quotes(EscapeMethod::Double, name);
+        name.make_ascii_uppercase();
+        if threading == Threading::Dtc {
+            format!("{name_sym}    .CODE_W {name_len}, \"{name}\", 0, {last_ref}")
+        } else {
+            format!("{name_sym}    .HIGH_W {name_len}, \"{name}\", {action}, , {last_ref}")
+        }
+    }
+}
+
+fn ref_str(s: &str) -> String {
+    if s.len() == 1 {
+        let first_char = s.chars().next().unwrap();
+        if first_char == '0' {
+            return s.to_string();
+        }
+    }
+    let s2 = format!("{}.cfa", s);
+    s2
+}
+
+impl FthGen for Ca6502 {
+    fn prolog(&mut self) {
+    }
+
+    fn do_literal(&mut self, n: i64) {
+        if self.threading == Threading::Stc {
+            self.flush_pending();
+            let l = n as u16;
+            self.push_value(&format!("${:02x}", l & 0xff), &format!("${:02x}", l >> 8));
+            return;
+        }
+        if self.threading == Threading::Token {
+            let t = self.token_for("lit", word_to_symbol("lit"));
+            self.out.line(format!("    .byte {}", Ca6502::token_bytes(t)));
+        } else {
+            self.out.line("    .word w_lit.cfa");
+        }
+        let l = n as i16;
+        if l < 0 {
+            self.out.line(format!("    .sint {l}"));
+        } else {
+            self.out.line(format!("    .word {l}"));
+        }
+    }
+
+    fn do_string_literal(&mut self, s: &str) {
+        self.flush_pending();
+        self.out.line(format!("    .text {}", tass_text(s)));
+    }
+
+    fn create_word(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        self.flush_pending();
         let last_ref = ref_str(&self.last_dict_entry);
-        println!("{word_sym}    .CODE_W {word_len}, \"{w}\", {flags}, {last_ref}");
-        println!("  .block");
+        let kind = if self.threading == Threading::Dtc { "CODE_W" } else { "HIGH_W" };
+        self.out.header(Ca6502::word_header(kind, w, sym, is_immediate, &last_ref));
+        self.out.line("  .block");
+        if self.threading == Threading::Dtc {
+            self.out.line("    JSR w_docol");
+        }
         if !unlisted {
-            self.last_dict_entry = word_sym.clone();
+            self.last_dict_entry = sym.to_string();
         }
     }
 
+    fn create_code(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        self.flush_pending();
+        let last_ref = ref_str(&self.last_dict_entry);
+        self.out.header(Ca6502::word_header("CODE_W", w, sym, is_immediate, &last_ref));
+        self.out.line("  .block");
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+
     fn close_definition(&mut self) {
-        println!("  .endblock");
+        self.flush_pending();
+        self.out.line("  .endblock");
     }
 
-    fn emit_word(&mut self, w: &str) {
-        let word_sym = word_to_symbol(&w);
+    fn emit_word(&mut self, w: &str, sym: &str) {
+        let word_sym = sym.to_string();
         let sym_ref = ref_str(&word_sym);
-        println!("    .addr {sym_ref}");
+        if self.threading == Threading::Stc {
+            if self.stc_operand(&sym_ref) {
+                return;
+            }
+            match word_sym.as_str() {
+                "w_lit" | "w_branch" | "w_qbranch" => self.pending = Some(word_sym),
+                "w_exit" => self.out.line("    RTS"),
+                _ => self.out.line(format!("    JSR {sym_ref}")),
+            }
+            return;
+        }
+        if self.threading == Threading::Token {
+            if self.operand_next {
+                self.operand_next = false;
+                self.out.line(format!("    .addr {sym_ref}"));
+                return;
+            }
+            self.operand_next = word_sym == "w_lit";
+            let t = self.token_for(w, word_sym);
+            self.out.line(format!("    .byte {}", Ca6502::token_bytes(t)));
+            return;
+        }
+        self.out.line(format!("    .addr {sym_ref}"));
+    }
+
+    fn emit_cell(&mut self, n: i64) {
+        self.flush_pending();
+        let l = n as i16;
+        if l < 0 {
+            self.out.line(format!("    .sint {l}"));
+        } else {
+            self.out.line(format!("    .word {l}"));
+        }
     }
 
     fn emit_lines(&mut self, lines: Vec<String>) {
+        self.flush_pending();
         for l in lines {
-            print!("{l}");
+            self.out.text(l);
         }
     }
 
     fn refer_to_label(&mut self, w: &str) {
-        println!("    .addr {w}");
+        if self.stc_operand(w) {
+            return;
+        }
+        self.operand_next = false;
+        self.out.line(format!("    .addr {w}"));
     }
 
     fn emit_label(&mut self, l: &str) {
-        println!("{l}");
+        self.flush_pending();
+        self.out.line(l);
     }
 
-    fn create_constant(&mut self, name: &str, val: i64, unlisted: bool) {
-        let name_sym = word_to_symbol(&name);
-        let name_len = name.len();
-        let mut name = escape_quotes(EscapeMethod::Double, name);
+    fn create_constant(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        self.flush_pending();
         let const_val = val as i32;
-        name.make_ascii_uppercase();
         let last_ref = ref_str(&self.last_dict_entry);
-        println!("{name_sym}    .HIGH_W {name_len}, \"{name}\", w_const, , {last_ref}");
+        self.out.header(Ca6502::data_header(name, sym, "w_const", self.threading, &last_ref));
+        if self.threading == Threading::Dtc {
+            self.out.line("    JSR w_const");
+        }
         if const_val < 0 {
-            println!("    .sint {const_val}");
+            self.out.line(format!("    .sint {const_val}"));
         } else {
-            println!("    .word {const_val}");
+            self.out.line(format!("    .word {const_val}"));
         }
         if !unlisted {
-            self.last_dict_entry = name_sym.clone();
+            self.last_dict_entry = sym.to_string();
         }
     }
 
-    fn create_variable(&mut self, name: &str, size: u8, unlisted: bool) {
-        let name_sym = word_to_symbol(&name);
-        let name_len = name.len();
-        let mut name = escape_quotes(EscapeMethod::Double, name);
-        name.make_ascii_uppercase();
+    fn create_variable(&mut self, name: &str, sym: &str, size: u8, unlisted: bool) {
+        self.flush_pending();
         let last_ref = ref_str(&self.last_dict_entry);
-        println!("{name_sym}    .HIGH_W {name_len}, \"{name}\", w_var, , {last_ref}");
+        self.out.header(Ca6502::data_header(name, sym, "w_var", self.threading, &last_ref));
+        if self.threading == Threading::Dtc {
+            self.out.line("    JSR w_var");
+        }
         for _ in 0..size {
-            println!("    .word 0");
+            self.out.line("    .word 0");
         }
         if !unlisted {
-            self.last_dict_entry = name_sym.clone();
+            self.last_dict_entry = sym.to_string();
         }
     }
 
+    fn set_wordlist(&mut self, head: &str) {
+        self.chains.switch(head, &mut self.last_dict_entry);
+    }
+
+    fn create_value(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        self.flush_pending();
+        let last_ref = ref_str(&self.last_dict_entry);
+        self.out.header(Ca6502::data_header(name, sym, "w_const", self.threading, &last_ref));
+        if self.threading == Threading::Dtc {
+            self.out.line("    JSR w_const");
+        }
+        self.out.line(format!("{sym}_body"));
+        self.emit_cell(val);
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn create_defer(&mut self, name: &str, sym: &str, action: Option<&str>, unlisted: bool) {
+        self.flush_pending();
+        let last_ref = ref_str(&self.last_dict_entry);
+        self.out.header(Ca6502::data_header(name, sym, "w_defer", self.threading, &last_ref));
+        if self.threading == Threading::Dtc {
+            self.out.line("    JSR w_defer");
+        }
+        self.out.line(format!("{sym}_body"));
+        let xt = action.map_or_else(|| String::from("0"), ref_str);
+        self.out.line(format!("    .addr {xt}"));
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn refer_to_body(&mut self, sym: &str) {
+        let body = format!("{sym}_body");
+        self.refer_to_label(&body);
+    }
+
+    fn create_with_action(&mut self, name: &str, sym: &str, action: &str, unlisted: bool) {
+        self.flush_pending();
+        let last_ref = ref_str(&self.last_dict_entry);
+        self.out.header(Ca6502::data_header(name, sym, action, self.threading, &last_ref));
+        if self.threading == Threading::Dtc {
+            self.out.line(format!("    JSR {action}"));
+        }
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+
+    fn start_does(&mut self, label: &str) {
+        self.flush_pending();
+        self.out.line(label);
+        self.out.line("  .block");
+        self.out.line("    JSR w_dodoes");
+    }
+
     fn allot_space(&mut self, size: u64) {
-        println!("    .fill {size}");
+        self.flush_pending();
+        self.out.line(format!("    .fill {size}"));
     }
 
-    fn epilog(&mut self) {
-        let de = ref_str(&self.last_dict_entry);
-        println!("dict_head .addr {de}");
+    fn epilog(&mut self) -> anyhow::Result<()> {
+        self.flush_pending();
+        for (head, de) in self.chains.all(&self.last_dict_entry) {
+            self.out.line(format!("{head} .addr {}", ref_str(&de)));
+        }
+        if self.threading == Threading::Token {
+            self.write_tokens()?;
+        }
+        self.out.finish()?;
+
+        Ok(())
+    }
+
+    fn token_map(&self) -> Option<String> {
+        self.token_map.clone()
     }
 }
 
@@ -842,97 +1659,259 @@ enum CondCompileState {
     CompileUntilElse,
 }
 
+/* Control structures still open in the definition being compiled,
+ * innermost last.  Each entry keeps the labels its closer still has to
+ * resolve and where its opener was, for the error when it is closed by
+ * the wrong word or not at all.  WHILE leaves its exit below the BEGIN,
+ * so REPEAT and UNTIL find the BEGIN on top and THEN can resolve a
+ * WHILE as well as an IF or ELSE.
+ */
+#[derive(Clone, Copy, PartialEq)]
+enum CtrlKind {
+    If,
+    Else,
+    Begin,
+    While,
+    Case,
+    Of,
+    Do,
+}
+
+impl CtrlKind {
+    fn name(self) -> &'static str {
+        match self {
+            CtrlKind::If => "IF",
+            CtrlKind::Else => "ELSE",
+            CtrlKind::Begin => "BEGIN",
+            CtrlKind::While => "WHILE",
+            CtrlKind::Case => "CASE",
+            CtrlKind::Of => "OF",
+            CtrlKind::Do => "DO",
+        }
+    }
+}
+
+enum Ctrl {
+    If(String),
+    Else(String),
+    Begin(String),
+    While(String),
+    Case(String),
+    Of(String),
+    Do { backward: String, forward: String },
+}
+
+impl Ctrl {
+    fn kind(&self) -> CtrlKind {
+        match self {
+            Ctrl::If(_) => CtrlKind::If,
+            Ctrl::Else(_) => CtrlKind::Else,
+            Ctrl::Begin(_) => CtrlKind::Begin,
+            Ctrl::While(_) => CtrlKind::While,
+            Ctrl::Case(_) => CtrlKind::Case,
+            Ctrl::Of(_) => CtrlKind::Of,
+            Ctrl::Do { .. } => CtrlKind::Do,
+        }
+    }
+
+    /* The one label of everything but DO. */
+    fn label(self) -> String {
+        match self {
+            Ctrl::If(l) | Ctrl::Else(l) | Ctrl::Begin(l) | Ctrl::While(l) |
+            Ctrl::Case(l) | Ctrl::Of(l) => l,
+            Ctrl::Do { forward, .. } => forward,
+        }
+    }
+}
+
+struct CtrlEntry {
+    ctrl: Ctrl,
+    loc: Option<Location>,
+}
+
 struct Fth {
-    gen: Box<dyn FthGen>,
+    ir: ir::Builder,
     defines: HashSet<String>,
     input_mgr: InputMgr,
     is_compiling: bool,
-    skip_stack: Vec<CondCompileState>,
+    skip_stack: Vec<(CondCompileState, Option<Location>)>,
     data_stack: Vec<i64>,
-    ctrl_do_stack: Vec<String>,
-    ctrl_other_stack: Vec<String>,
+    ctrl_stack: Vec<CtrlEntry>,
+    open_def: Option<(String, Option<Location>)>,
     next_label: u32,
     next_is_immediate: bool,
     next_is_unlisted: bool,
+    next_is_host: bool,
+    last_is_word: bool,
+    host_words: HashMap<String, HostWord>,
+    constants: HashMap<String, i64>,
+    symbols: SymbolTable,
+    check_undefined: bool,
+    stack_check: StackCheck,
+    awaiting_effect: Option<String>,
+    ticked: Option<(String, usize)>,
+    /* Words compiled before anything of their name was in the search
+     * order, to be looked up again once the whole source has been read.
+     */
+    forward: Vec<(ir::At, String, Rc<Vec<usize>>)>,
+    ascii_names: bool,
+    cell_size: Option<i64>,
+    search: SearchOrder,
 }
 
 impl Fth {
-    pub fn new(arch: Arch, defines: Option<String>) -> Fth {
-        let g: Box<dyn FthGen> = match arch {
-            Arch::C => panic!("C not supported yet"),
-            Arch::AttAsm32 => Box::new(AttGen::new()),
-            Arch::Ca6502 => Box::new(Ca6502::new()),
-        };
-        let def_strings = defines.unwrap_or(String::new());
+    pub fn new(cli: &Args) -> Fth {
+        let defines = cli.defines.clone();
+        let def_strings = defines.unwrap_or_default();
         let def_strings: Vec<String> =
-            def_strings.split_terminator(',').map(|sp| String::from(sp)).collect();
+            def_strings.split_terminator(',').map(String::from).collect();
         let mut defines_set: HashSet<String> = HashSet::new();
         for s in def_strings {
             defines_set.insert(s);
         }
 
         Fth {
-            gen: g,
+            ir: ir::Builder::new(),
             defines: defines_set,
-            input_mgr: InputMgr::new(),
+            input_mgr: InputMgr::new(cli.include_dirs.clone(), cli.encoding, cli.non_ascii),
             is_compiling: false,
             skip_stack: Vec::new(),
             data_stack: Vec::new(),
-            ctrl_do_stack: Vec::new(),
-            ctrl_other_stack: Vec::new(),
+            ctrl_stack: Vec::new(),
+            open_def: None,
             next_label: 1,
             next_is_immediate: false,
             next_is_unlisted: false,
+            next_is_host: false,
+            last_is_word: false,
+            host_words: HashMap::new(),
+            constants: HashMap::new(),
+            symbols: SymbolTable::new(),
+            check_undefined: !cli.allow_undefined,
+            stack_check: cli.stack_check,
+            awaiting_effect: None,
+            ticked: None,
+            forward: Vec::new(),
+            ascii_names: cli.arch.iter().any(|a| matches!(a, Arch::Ca6502 | Arch::Ca65816)),
+            cell_size: common_cell_size(cli),
+            search: SearchOrder::new(),
         }
     }
 
-    fn do_skip(&mut self, w: &str) -> bool {
+    fn do_skip(&mut self, w: &str) -> anyhow::Result<bool> {
         let w = w.to_uppercase();
 
         if self.skip_stack.is_empty() {
             if w == "[THEN]" || w == "[ELSE]" {
-                panic!("Encountered {w} without matching [IF]");
+                anyhow::bail!("Encountered {w} without matching [IF]");
             }
-            return false;
+            return Ok(false);
         }
 
-        let cur_action = *self.skip_stack.last().unwrap();
+        let cur_action = self.skip_stack.last().unwrap().0;
         let is_skipping = cur_action == CondCompileState::Skipping ||
             cur_action == CondCompileState::SkipUntilElse;
 
+        /* Comments are still comments in the text being skipped. */
+        if is_skipping && w == "\\" {
+            self.input_mgr.skip_line();
+            return Ok(true);
+        }
+        if is_skipping && w == "(" {
+            self.input_mgr.str_by(|c: char| c == ')')?;
+            return Ok(true);
+        }
+
         if w == "[ELSE]" {
             match cur_action {
                 CondCompileState::SkipUntilElse => {
-                    self.skip_stack.pop();
-                    self.skip_stack.push(CondCompileState::CompileUntilElse);
+                    self.skip_stack.last_mut().unwrap().0 = CondCompileState::CompileUntilElse;
                 }
                 CondCompileState::CompileUntilElse => {
-                    self.skip_stack.pop();
-                    self.skip_stack.push(CondCompileState::SkipUntilElse);
+                    self.skip_stack.last_mut().unwrap().0 = CondCompileState::SkipUntilElse;
                 }
                 CondCompileState::Skipping => {
                     // Do nothing.  Keep skip nesting constant here, but
                     // also keep on skipping
                 }
             }
-            return true
+            return Ok(true)
         }
 
         if w == "[THEN]" {
             self.skip_stack.pop();
-            return true
+            return Ok(true)
         }
 
         // Hitting [IF] (or [THEN]) while skipping is special because we have to
         // track nesting.  And [IF] is handled if _not_ skipping via its action.
-        if is_skipping {
-            if w == "[IF]" {
-                self.skip_stack.push(CondCompileState::Skipping);
-                return true;
+        if is_skipping && w == "[IF]" {
+            self.skip_stack.push((CondCompileState::Skipping, self.input_mgr.location()));
+            return Ok(true);
+        }
+
+        Ok(is_skipping)
+    }
+
+    fn push_ctrl(&mut self, ctrl: Ctrl) {
+        let loc = self.input_mgr.location();
+        self.ctrl_stack.push(CtrlEntry { ctrl, loc });
+    }
+
+    /* Take the innermost open structure, which `closer` expects to be
+     * one of `kinds`.
+     */
+    fn pop_ctrl(&mut self, closer: &str, kinds: &[CtrlKind]) -> anyhow::Result<CtrlEntry> {
+        let names: Vec<&str> = kinds.iter().map(|k| k.name()).collect();
+        let Some(top) = self.ctrl_stack.last() else {
+            anyhow::bail!("{closer} without {}", names.join(" or "));
+        };
+        let kind = top.ctrl.kind();
+        if !kinds.contains(&kind) {
+            let mut msg = format!("{closer} does not match {}", kind.name());
+            if let Some(l) = &top.loc {
+                msg.push_str(&format!("\n    note: {} is at {l}", kind.name()));
+            }
+            anyhow::bail!(msg);
+        }
+        Ok(self.ctrl_stack.pop().unwrap())
+    }
+
+    /* Everything opened in a definition has to be closed by the time
+     * `end` ends it.  The errors point at the openers.
+     */
+    fn check_ctrl_closed(&mut self, end: &str) -> anyhow::Result<()> {
+        if self.ctrl_stack.is_empty() {
+            return Ok(());
+        }
+        let end_loc = self.input_mgr.location();
+        let mut errors = Vec::new();
+        for e in self.ctrl_stack.drain(..) {
+            let mut msg = format!("{} is not closed", e.ctrl.kind().name());
+            if let Some(l) = &end_loc {
+                msg.push_str(&format!("\n    note: {end} at {l} ends the definition"));
             }
+            errors.push(Diagnostic::error(e.loc, msg));
         }
+        Err(Diagnostics(errors).into())
+    }
 
-        is_skipping
+    /* What is still open when the input runs out. */
+    fn check_eof(&mut self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        for (_, loc) in self.skip_stack.drain(..) {
+            errors.push(Diagnostic::error(loc, "[IF] without [THEN]".to_string()));
+        }
+        for e in self.ctrl_stack.drain(..) {
+            errors.push(Diagnostic::error(e.loc, format!("{} is not closed", e.ctrl.kind().name())));
+        }
+        if let Some((name, loc)) = self.open_def.take() {
+            errors.push(Diagnostic::error(loc, format!("Definition of '{name}' is not ended by ';'")));
+        }
+        if !errors.is_empty() {
+            return Err(Diagnostics(errors).into());
+        }
+        Ok(())
     }
 
     fn new_label(&mut self) -> String {
@@ -943,119 +1922,728 @@ impl Fth {
     }
 
     fn do_literal(&mut self, n: i64) {
-        self.gen.do_literal(n);
+        self.ir.do_literal(n);
     }
 
     fn do_string_literal(&mut self, s: &str) {
-        self.gen.do_string_literal(s);
+        self.ir.do_string_literal(s);
     }
 
     fn do_number(&mut self, n: i64) {
         if self.is_compiling {
-            self.gen.do_literal(n);
+            self.ir.do_literal(n);
         } else {
             self.data_stack.push(n);
         }
     }
 
+    fn define(&mut self, name: &str, kind: SymKind) {
+        let loc = self.input_mgr.location();
+        self.symbols.define(name, kind, loc, self.search.current());
+    }
+
     fn create_word(&mut self, w: &str, is_immediate: bool, unlisted: bool) {
-        self.gen.create_word(w, is_immediate, unlisted);
+        self.define(w, SymKind::Colon);
+        self.awaiting_effect = Some(w.to_string());
+        self.last_is_word = true;
+        self.ir.create_word(w, is_immediate, unlisted);
     }
 
     fn create_code(&mut self, w: &str, is_immediate: bool, unlisted: bool) {
-        self.gen.create_code(w, is_immediate, unlisted);
+        self.define(w, SymKind::Code);
+        self.awaiting_effect = Some(w.to_string());
+        self.last_is_word = true;
+        self.ir.create_code(w, is_immediate, unlisted);
     }
 
     fn close_definition(&mut self) {
-        self.gen.close_definition();
+        self.ir.close_definition();
     }
 
     fn emit_word(&mut self, w: &str) {
-        self.gen.emit_word(w);
+        self.ir.emit_word(w, FORTH);
+    }
+
+    /* Compile a word named in the source, as opposed to one the control
+     * words lay down for the kernel, and remember where for check().
+     */
+    fn use_word(&mut self, w: &str) {
+        let loc = self.input_mgr.location();
+        let order = self.search.order();
+        match self.symbols.find(w, &order) {
+            Some(s) => self.ir.emit_word(w, s.wordlist),
+            None => {
+                self.ir.emit_word(w, FORTH);
+                self.forward.push((self.ir.last_item(), w.to_string(), order.clone()));
+            }
+        }
+        self.symbols.add_use(w, loc, order);
     }
 
     fn refer_to_label(&mut self, w: &str) {
-        self.gen.refer_to_label(w);
+        self.ir.refer_to_label(w);
     }
 
     fn emit_label(&mut self, l: &str) {
-        self.gen.emit_label(l);
+        self.ir.emit_label(l);
     }
 
     fn create_constant(&mut self, name: &str, val: i64, unlisted: bool) {
-        self.gen.create_constant(name, val, unlisted);
+        self.define(name, SymKind::Constant);
+        self.last_is_word = false;
+        self.constants.insert(name.to_uppercase(), val);
+        self.ir.create_constant(name, val, unlisted);
     }
 
     fn create_variable(&mut self, name: &str, size: u8, unlisted: bool) {
-        self.gen.create_variable(name, size, unlisted);
+        self.define(name, SymKind::Variable);
+        self.last_is_word = false;
+        self.ir.create_variable(name, size, unlisted);
+    }
+
+    fn create_value(&mut self, name: &str, val: i64, unlisted: bool) {
+        self.define(name, SymKind::Value);
+        self.last_is_word = false;
+        self.ir.create_value(name, val, unlisted);
+    }
+
+    fn create_defer(&mut self, name: &str, unlisted: bool) {
+        self.define(name, SymKind::Defer);
+        self.last_is_word = false;
+        self.ir.create_defer(name, unlisted);
+    }
+
+    fn store_to_body(&mut self, name: &str, wordlist: usize) {
+        self.emit_word("lit");
+        self.ir.refer_to_body(name, wordlist);
+        self.emit_word("!");
+    }
+
+    fn create(&mut self, name: &str, unlisted: bool) {
+        self.define(name, SymKind::Created);
+        self.last_is_word = false;
+        self.ir.create(name, unlisted);
     }
 
     fn allot_space(&mut self, size: u64) {
-        self.gen.allot_space(size);
+        self.ir.allot_space(size);
     }
 
     fn emit_lines(&mut self, lines: Vec<String>) {
-        self.gen.emit_lines(lines);
+        self.ir.emit_lines(lines);
+    }
+
+    fn interpret_word(&mut self, w: &str) -> anyhow::Result<()> {
+        if w != "(" && w != "\\" {
+            self.awaiting_effect = None;
+            if !w.eq_ignore_ascii_case("IS") {
+                self.ticked = None;
+            }
+        }
+        if self.do_skip(w)? {
+            // [IF], [ELSE], [THEN] are "special"
+            return Ok(());
+        }
+
+        let upper_w = w.to_uppercase();
+        if self.host_words.contains_key(&upper_w) {
+            return host::execute(self, w);
+        }
+        if !self.is_compiling {
+            if let Some(action) = SEARCH_WORDS.get(&*upper_w) {
+                return action(self);
+            }
+            if let Some(wid) = self.search.vocabulary(&upper_w) {
+                self.search.select(wid);
+                return Ok(());
+            }
+        }
+        match ACTIVE_WORDS.get(&*upper_w) {
+            None => {
+                match parse_number(w)? {
+                    Some(n) => {
+                        self.do_number(n);
+                    }
+                    None => {
+                        if self.is_compiling {
+                            self.use_word(w);
+                        } else if host::is_host_word(self, &upper_w) {
+                            host::execute(self, w)?;
+                        } else if let Some(&v) = self.constants.get(&upper_w) {
+                            self.data_stack.push(v);
+                        } else {
+                            anyhow::bail!("Unknown word '{w}' outside of a definition");
+                        }
+                    }
+                }
+            }
+            Some(action) => {
+                action(self)?;
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn interpret(&mut self, in_file: &str) -> anyhow::Result<()> {
+    /* Attach the location of the word being interpreted to e, unless
+     * something further down already did.
+     */
+    fn located(&self, e: anyhow::Error) -> anyhow::Error {
+        if e.is::<Diagnostic>() || e.is::<Diagnostics>() {
+            return e;
+        }
+        match self.input_mgr.location() {
+            None => e,
+            Some(loc) => Diagnostic::error(loc, format!("{e:#}")).into(),
+        }
+    }
+
+    pub fn interpret(mut self, in_file: &str) -> anyhow::Result<Program> {
         self.input_mgr.open_file(in_file)?;
 
-        self.gen.prolog();
         loop {
             self.input_mgr.skip_ws()?;
             let w = self.input_mgr.word()?;
             match w {
                 None => break,
                 Some(w) => {
-
-                    if self.do_skip(&w) {
-                        // [IF], [ELSE], [THEN] are "special"
-                        continue;
-                    }
-
-                    let upper_w = w.to_uppercase();
-                    match ACTIVE_WORDS.get(&*upper_w) {
-                        None => {
-                            if w.starts_with("0x") {
-                                let n = i64::from_str_radix(&w[2..], 16)?;
-                                self.do_number(n);
-                            } else if w.starts_with("0b") {
-                                let n = i64::from_str_radix(&w[2..], 2)?;
-                                self.do_number(n);
-                            } else {
-                                match i64::from_str_radix(&*w, 10) {
-                                    Ok(n) => {
-                                        self.do_number(n);
-                                    }
-                                    Err(_) => {
-                                        if self.is_compiling {
-                                            self.emit_word(&w);
-                                        } else {
-                                            // FIXME
-                                            println!("*** FIXME: handle bad immediate: '{w}'");
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        Some(action) => {
-                            action(self)?;
-                        }
+                    if let Err(e) = self.interpret_word(&w) {
+                        return Err(self.located(e));
                     }
                 }
             }
         }
-        self.gen.epilog();
+        self.check_eof()?;
+        if self.check_undefined {
+            self.symbols.check(self.search.names())?;
+        }
+        for (at, w, order) in &self.forward {
+            if let Some(s) = self.symbols.find(w, order) {
+                self.ir.rebind(*at, s.wordlist);
+            }
+        }
+        let prog = self.ir.finish(self.search.heads());
+        if self.ascii_names {
+            check_header_names(&prog, &self.symbols)?;
+        }
+        stack_check::check(&prog, &self.symbols, self.stack_check)?;
+
+        Ok(prog)
+    }
+}
 
+/* The 64tass header macros take the name as one quoted string, and
+ * there is no way to spell out UTF-8 bytes in it, so the 6502 and
+ * 65816 backends need ASCII names.
+ */
+fn check_header_names(prog: &Program, symbols: &SymbolTable) -> anyhow::Result<()> {
+    let mut errors = Vec::new();
+    for d in &prog.defs {
+        let Some(h) = &d.header else {
+            continue;
+        };
+        if matches!(h, ir::Header::Does { .. }) || h.name().is_ascii() {
+            continue;
+        }
+        let loc = symbols.lookup(h.name(), d.wordlist).and_then(|s| s.loc.clone());
+        errors.push(Diagnostic::error(loc, format!(
+            "'{}' is not ASCII, which the 6502 and 65816 headers need; see --non-ascii", h.name())));
+    }
+    if !errors.is_empty() {
+        return Err(Diagnostics(errors).into());
+    }
+    Ok(())
+}
+
+/* Reject option combinations before any source is read. */
+fn check_options(cli: &Args) -> anyhow::Result<()> {
+    let is_65816 = cli.arch.contains(&Arch::Ca65816);
+    if !is_65816 && (cli.addr_bits.is_some() || cli.cell_bits.is_some()) {
+        anyhow::bail!("--addr-bits and --cell-bits only apply to --arch ca65816");
+    }
+    let addr_bits = cli.addr_bits.unwrap_or(16);
+    let cell_bits = cli.cell_bits.unwrap_or(16);
+    if addr_bits != 16 && addr_bits != 24 {
+        anyhow::bail!("--addr-bits must be 16 or 24");
+    }
+    if cell_bits != 16 && cell_bits != 32 {
+        anyhow::bail!("--cell-bits must be 16 or 32");
+    }
+    for &arch in &cli.arch {
+        if cli.threading == Threading::Stc && arch != Arch::Ca6502 {
+            anyhow::bail!("--threading stc is not supported for this arch");
+        }
+        if cli.threading == Threading::Token && arch != Arch::Ca6502 {
+            anyhow::bail!("--threading token is not supported for this arch");
+        }
+    }
+    if cli.token_map.is_some() && cli.threading != Threading::Token {
+        anyhow::bail!("--token-map only applies to --threading token");
+    }
+    if cli.arch.len() > 1 && cli.output.is_none() {
+        anyhow::bail!("-o is required with more than one --arch");
+    }
+    Ok(())
+}
+
+fn make_gen(cli: &Args, arch: Arch, out: Box<dyn Write>) -> anyhow::Result<Box<dyn FthGen>> {
+    let addr_bits = cli.addr_bits.unwrap_or(16);
+    let cell_bits = cli.cell_bits.unwrap_or(16);
+    let g: Box<dyn FthGen> = match arch {
+        Arch::C => Box::new(CGen::new(out, cli.threading)),
+        Arch::AttAsm32 => Box::new(AttGen::new(out, false, cli.threading)),
+        Arch::AttAsm64 => Box::new(AttGen::new(out, true, cli.threading)),
+        Arch::Ca6502 => Box::new(Ca6502::new(out, cli.threading)),
+        Arch::Ca65816 => Box::new(Ca65816::new(out, addr_bits, cell_bits, cli.threading)),
+        Arch::Z80 => Box::new(Z80Gen::new(out, cli.threading)),
+        Arch::Riscv32 => Box::new(Rv32Gen::new(out, cli.threading)),
+    };
+    Ok(g)
+}
+
+/* Parse the source and run the requested passes over the result. */
+fn compile(cli: &Args) -> anyhow::Result<Program> {
+    let fth = Fth::new(cli);
+    let mut prog = fth.interpret(&cli.filename)?;
+    if let Some(roots) = &cli.strip_unused {
+        let roots: Vec<String> = roots.split_terminator(',').map(String::from).collect();
+        shake::strip_unused(&mut prog, &roots)?;
+    }
+    if cli.peephole || cli.peephole_rules.is_some() {
+        let mut rules = Vec::new();
+        if let Some(path) = &cli.peephole_rules {
+            rules.extend(peephole::load_rules(path)?);
+        }
+        if cli.peephole {
+            rules.extend(peephole::builtin_rules()?);
+        }
+        peephole::optimize(&mut prog, &rules);
+    }
+    Ok(prog)
+}
+
+/* With -o every file goes to a scratch file next to the real one, and
+ * only once all of them (one per --arch, and the token map) have been
+ * generated are they renamed into place, so a failed run never leaves
+ * a truncated or stale .s file behind.  Whatever is still staged when
+ * this is dropped gets removed.
+ */
+struct Staged(Vec<(String, String)>);
+
+impl Staged {
+    fn write(&mut self, output: &str, write: impl FnOnce(Box<dyn Write>) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let tmp_name = format!("{output}.tmp");
+        let f = File::create(&tmp_name).with_context(|| format!("Cannot create '{tmp_name}'"))?;
+        self.0.push((tmp_name, output.to_string()));
+        write(Box::new(BufWriter::new(f)))
+    }
+
+    fn commit(mut self) -> anyhow::Result<()> {
+        for (tmp_name, output) in self.0.drain(..) {
+            fs::rename(&tmp_name, &output).with_context(|| format!("Cannot write '{output}'"))?;
+        }
         Ok(())
     }
 }
 
-fn main() -> anyhow::Result<()> {
+impl Drop for Staged {
+    fn drop(&mut self) {
+        for (tmp_name, _) in &self.0 {
+            let _ = fs::remove_file(tmp_name);
+        }
+    }
+}
+
+/* Bytes in a cell of `arch`, for CELLS.  C cells are intptr_t, so they
+ * count as 8 bytes, which is enough room on any host.
+ */
+fn cell_size(cli: &Args, arch: Arch) -> i64 {
+    match arch {
+        Arch::C | Arch::AttAsm64 => 8,
+        Arch::AttAsm32 | Arch::Riscv32 => 4,
+        Arch::Ca6502 | Arch::Z80 => 2,
+        Arch::Ca65816 => cli.cell_bits.unwrap_or(16) as i64 / 8,
+    }
+}
+
+/* None when the --arch list has cells of different sizes. */
+fn common_cell_size(cli: &Args) -> Option<i64> {
+    let mut sizes = cli.arch.iter().map(|a| cell_size(cli, *a));
+    let first = sizes.next()?;
+    sizes.all(|s| s == first).then_some(first)
+}
+
+/* `fth.s` becomes `fth.z80.s` when several arches are generated. */
+fn output_name(output: &str, arch: Arch, several: bool) -> String {
+    if !several {
+        return output.to_string();
+    }
+    let name = arch.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+    match output.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.contains('/') => format!("{stem}.{name}.{ext}"),
+        _ => format!("{output}.{name}"),
+    }
+}
+
+fn run(cli: &Args) -> anyhow::Result<()> {
+    check_options(cli)?;
+    let prog = compile(cli)?;
+    if cli.stats {
+        eprintln!("{}", prog.stats());
+    }
+    let several = cli.arch.len() > 1;
+    let mut staged = Staged(Vec::new());
+    for &arch in &cli.arch {
+        let mut token_map = None;
+        let mut emit = |out: Box<dyn Write>| -> anyhow::Result<()> {
+            let mut g = make_gen(cli, arch, out)?;
+            prog.emit(g.as_mut())?;
+            token_map = g.token_map();
+            Ok(())
+        };
+        match &cli.output {
+            None => emit(Box::new(BufWriter::new(io::stdout())))?,
+            Some(output) => staged.write(&output_name(output, arch, several), emit)?,
+        }
+        /* Only once the code it describes has been generated. */
+        if let (Some(path), Some(map)) = (&cli.token_map, token_map) {
+            staged.write(path, |mut out| {
+                out.write_all(map.as_bytes())?;
+                out.flush()?;
+                Ok(())
+            }).context("Cannot write the token map")?;
+        }
+    }
+    staged.commit()
+}
+
+fn main() -> ExitCode {
     let cli = Args::parse();
-    let mut fth = Fth::new(cli.arch, cli.defines);
-    fth.interpret(&cli.filename)?;
+    if let Err(e) = run(&cli) {
+        if e.is::<Diagnostic>() || e.is::<Diagnostics>() {
+            eprintln!("{e}");
+        } else {
+            eprintln!("rfc: error: {e:#}");
+        }
+        return ExitCode::FAILURE;
+    }
 
-    Ok(())
+    ExitCode::SUCCESS
+}
+
+/* Running the whole front end, and a backend, on source written by a
+ * test.
+ */
+#[cfg(test)]
+pub mod testing {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use clap::Parser;
+
+    use crate::ir::{Item, Program};
+    use crate::{check_options, compile, make_gen, out_buf, Args};
+
+    /* A scratch directory for source files, removed again when dropped. */
+    pub struct Scratch(PathBuf);
+
+    impl Scratch {
+        pub fn new() -> Scratch {
+            static N: AtomicUsize = AtomicUsize::new(0);
+            let n = N.fetch_add(1, Ordering::Relaxed);
+            let dir = std::env::temp_dir().join(format!("rfc-test-{}-{n}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        pub fn path(&self, name: &str) -> String {
+            self.0.join(name).to_str().unwrap().to_string()
+        }
+
+        /* Write `name`, which may have directories in it, and return
+         * its path.
+         */
+        pub fn file(&self, name: &str, text: impl AsRef<[u8]>) -> String {
+            let path = self.path(name);
+            fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+            fs::write(&path, text).unwrap();
+            path
+        }
+    }
+
+    impl Default for Scratch {
+        fn default() -> Scratch {
+            Scratch::new()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /* Compile `path` with these options, --arch c unless they say. */
+    pub fn compile_file(path: &str, opts: &[&str]) -> anyhow::Result<Program> {
+        let mut argv = vec!["rfc"];
+        if !opts.iter().any(|o| *o == "-a" || *o == "--arch") {
+            argv.extend(["-a", "c"]);
+        }
+        argv.extend_from_slice(opts);
+        argv.push(path);
+        compile(&Args::parse_from(argv))
+    }
+
+    pub fn compile_with(text: &str, opts: &[&str]) -> anyhow::Result<Program> {
+        let dir = Scratch::new();
+        compile_file(&dir.file("main.fs", text), opts)
+    }
+
+    /* The output of the first --arch in `opts` for `text`. */
+    pub fn emit_with(text: &str, opts: &[&str]) -> anyhow::Result<String> {
+        let dir = Scratch::new();
+        let mut argv = vec!["rfc"];
+        argv.extend_from_slice(opts);
+        let path = dir.file("main.fs", text);
+        argv.push(&path);
+        let cli = Args::parse_from(argv);
+        check_options(&cli)?;
+        let prog = compile(&cli)?;
+        let out = out_buf::capture::Capture::default();
+        let mut g = make_gen(&cli, cli.arch[0], Box::new(out.clone()))?;
+        prog.emit(g.as_mut())?;
+        Ok(out.text())
+    }
+
+    /* What was compiled into the word `name`. */
+    pub fn body_of<'a>(prog: &'a Program, name: &str) -> &'a [Item] {
+        let d = prog.defs.iter().find(|d| d.header.as_ref().is_some_and(|h| h.name() == name));
+        &d.unwrap_or_else(|| panic!("no word '{name}'")).body
+    }
+
+    /* The names of all headers, in order. */
+    pub fn names(prog: &Program) -> Vec<&str> {
+        prog.defs.iter().filter_map(|d| d.header.as_ref()).map(|h| h.name()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{compile_with, emit_with, names, Scratch};
+
+    #[test]
+    fn skipped_comments_do_not_end_the_skip() {
+        let src = "0 [IF]\n\\ [THEN]\n( [ELSE] )\n: gone ;\n[ELSE]\n: kept ( -- ) ;\n\\ [ELSE]\n[THEN]\n";
+        let prog = compile_with(src, &[]).unwrap();
+        assert_eq!(names(&prog), ["kept"]);
+    }
+
+    #[test]
+    fn each_wordlist_has_its_own_symbols() {
+        let src = "\
+: helper ( -- ) ;
+VOCABULARY inner
+ALSO inner DEFINITIONS
+: early ( -- ) helper ;
+: late ( -- ) fwd ;
+: helper ( -- ) ;
+: fwd ( -- ) helper ;
+0 VALUE v
+: set ( n -- ) TO v ;
+PREVIOUS DEFINITIONS
+: outer ( -- ) helper ;
+";
+        let asm = emit_with(src, &["-a", "att-asm32", "--allow-undefined"]).unwrap();
+        let body = |w: &str| -> Vec<&str> {
+            asm.lines()
+                .skip_while(|l| !l.contains(&format!(" {w} ")))
+                .skip(1)
+                .take_while(|l| l.starts_with("    ."))
+                .map(str::trim)
+                .collect()
+        };
+        assert!(asm.contains("HIGH_W w_helper 6 \"helper\""), "{asm}");
+        assert!(asm.contains("HIGH_W w1_helper 6 \"helper\""), "{asm}");
+        assert_eq!(body("w1_early"), [".int w_helper", ".int w_exit"]);
+        assert_eq!(body("w1_late"), [".int w1_fwd", ".int w_exit"]);
+        assert_eq!(body("w1_fwd"), [".int w1_helper", ".int w_exit"]);
+        assert_eq!(body("w1_set"), [".int w_lit", ".int w1_v_body", ".int w_store", ".int w_exit"]);
+        assert_eq!(body("w_outer"), [".int w_helper", ".int w_exit"]);
+        assert!(asm.contains("dict_head_inner: .int dict_w1_set"), "{asm}");
+
+        let asm = emit_with(src, &["-a", "att-asm32", "--allow-undefined", "--strip-unused", "outer"]).unwrap();
+        assert!(!asm.contains("w1_helper"), "{asm}");
+    }
+
+    #[test]
+    fn hidden_words_are_reported() {
+        let src = "VOCABULARY hidden\nALSO hidden DEFINITIONS\n: helper ( -- ) ;\nPREVIOUS DEFINITIONS\n: bad ( -- ) helper ;\n";
+        let Err(e) = compile_with(src, &[]) else {
+            panic!("'helper' was found outside its wordlist");
+        };
+        assert!(format!("{e}").contains("'helper' is in hidden, which is not in the search order"), "{e}");
+    }
+
+    #[test]
+    fn gnu_as_strings_escape_backslashes_and_quotes() {
+        assert_eq!(escape_quotes(EscapeMethod::Backslash, r#"a\b"c"#), r#"a\\b\"c"#);
+        assert_eq!(escape_quotes(EscapeMethod::Backslash, "é"), "\\303\\251");
+        assert_eq!(escape_quotes(EscapeMethod::Double, r#"a\b"c"#), r#"a\b""c"#);
+
+        let asm = emit_with(": t s\" a\\b\" ;\n", &["-a", "att-asm32"]).unwrap();
+        assert!(asm.contains(r#".ascii "a\\b""#), "{asm}");
+    }
+
+    #[test]
+    fn code_words_take_their_effect_from_the_code_line() {
+        let src = "CODE dup ( a -- a a )\n    movl (%esp), %eax\nEND-CODE\n: bad ( a -- ) dup ;\n";
+        let Err(e) = compile_with(src, &["--stack-check", "error"]) else {
+            panic!("'bad' passed the stack check");
+        };
+        assert!(format!("{e}").contains("'bad'"), "{e}");
+
+        let prog = compile_with("CODE dup ( a -- a a ) movl (%esp), %eax\nEND-CODE\n", &[]).unwrap();
+        let ir::Item::Asm(lines) = &prog.defs[0].body[0] else {
+            panic!("{:?}", prog.defs[0].body);
+        };
+        assert_eq!(lines[0].trim(), "movl (%esp), %eax");
+        assert!(!lines.iter().any(|l| l.contains("--")), "{lines:?}");
+    }
+
+    #[test]
+    fn att_asm64_uses_quad_cells_and_realigns_strings() {
+        let src = "CODE dup ( a -- a a )\n    movq (%rsp), %rax\nEND-CODE\n: t ( -- a b c d ) -5 s\" abc\" dup ;\nVARIABLE v\n";
+        let asm = emit_with(src, &["-a", "att-asm64"]).unwrap();
+        assert!(asm.contains("    CODE_W w_dup 3 \"dup\" flgs=0\n    movq (%rsp), %rax\n    NEXT\n"), "{asm}");
+        assert!(asm.contains("    .quad w_lit\n    .quad -5\n"), "{asm}");
+        assert!(asm.contains("    .ascii \"abc\"\n    .balign 8\n_L001:\n"), "{asm}");
+        assert!(asm.contains("    HIGH_W w_v 1 \"v\" act=w_do_var\n    .quad 0\n"), "{asm}");
+        assert!(asm.contains("dict_head: .quad dict_w_v"), "{asm}");
+        assert!(!asm.contains(".int"), "{asm}");
+    }
+
+    const KERNEL_6502: &str = "\
+CODE dup ( a -- a a )
+    lda 0,x
+END-CODE
+: t ( -- a b ) 5 dup IF dup THEN ;
+: u ( -- a b ) t ;
+";
+
+    #[test]
+    fn stc_6502_calls_words_and_inlines_literals() {
+        let asm = emit_with(KERNEL_6502, &["-a", "ca6502", "-t", "stc", "--stack-check", "off"]).unwrap();
+        assert!(asm.contains("    DEX\n    DEX\n    LDA #$05\n    STA 0,x\n    LDA #$00\n    STA 1,x\n    JSR w_dup.cfa\n"), "{asm}");
+        assert!(asm.contains("    BNE +\n    JMP _L001\n+\n    JSR w_dup.cfa\n_L001\n    RTS\n"), "{asm}");
+        assert!(asm.contains("w_u    .HIGH_W 1, \"U\", , 0, w_t.cfa\n  .block\n    JSR w_t.cfa\n    RTS\n"), "{asm}");
+    }
+
+    #[test]
+    fn token_6502_numbers_words_in_order_of_use() {
+        assert_eq!(Ca6502::token_bytes(5), "$05");
+        assert_eq!(Ca6502::token_bytes(TOKEN_ESCAPE), "$f0, $00");
+        assert_eq!(Ca6502::token_bytes(TOKEN_ESCAPE + 0x123), "$f1, $23");
+
+        let dir = Scratch::new();
+        let src = dir.file("k.fs", KERNEL_6502);
+        let (out, map) = (dir.path("k.s"), dir.path("k.map"));
+        let cli = Args::parse_from(["rfc", "-a", "ca6502", "-t", "token", "--stack-check", "off",
+                                    "-o", &out, "--token-map", &map, &src]);
+        run(&cli).unwrap();
+        let asm = fs::read_to_string(&out).unwrap();
+        assert!(asm.contains("    .byte $00\n    .word 5\n    .byte $01\n    .byte $02\n    .addr _L001\n"), "{asm}");
+        assert!(asm.contains("token_table\n    .addr w_lit.cfa\n    .addr w_dup.cfa\n"), "{asm}");
+        assert_eq!(
+            fs::read_to_string(&map).unwrap(),
+            "0\t$00\tw_lit\tlit\n1\t$01\tw_dup\tdup\n2\t$02\tw_qbranch\tqbranch\n3\t$03\tw_exit\texit\n4\t$04\tw_t\tt\n"
+        );
+    }
+
+    fn compile_error(src: &str) -> String {
+        match compile_with(src, &["--allow-undefined"]) {
+            Ok(_) => panic!("{src:?} compiled"),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    #[test]
+    fn mismatched_control_structures_are_reported() {
+        let e = compile_error(": a ( -- ) BEGIN THEN ;\n");
+        assert!(e.contains("main.fs:1:18: error: THEN does not match BEGIN\n    note: BEGIN is at "), "{e}");
+        assert!(e.ends_with("main.fs:1:12"), "{e}");
+
+        let e = compile_error(": c ( -- ) THEN ;\n");
+        assert!(e.contains("main.fs:1:12: error: THEN without IF or ELSE or WHILE"), "{e}");
+    }
+
+    #[test]
+    fn unclosed_control_structures_are_reported() {
+        let e = compile_error(": b ( -- ) IF BEGIN ;\n");
+        assert!(e.contains("main.fs:1:12: error: IF is not closed\n    note: ';' at "), "{e}");
+        assert!(e.contains("main.fs:1:15: error: BEGIN is not closed"), "{e}");
+
+        let e = compile_error(": e ( -- ) 1 IF\n");
+        assert!(e.contains("main.fs:1:14: error: IF is not closed"), "{e}");
+        assert!(e.contains("main.fs:1:3: error: Definition of 'e' is not ended by ';'"), "{e}");
+
+        let e = compile_error("0 [IF]\n: d ( -- ) IF\n");
+        assert!(e.contains("main.fs:1:3: error: [IF] without [THEN]"), "{e}");
+    }
+
+    #[test]
+    fn create_does_words_run_their_does_body() {
+        let src = "\
+CODE @ ( a -- x )
+END-CODE
+: CONST ( n -- ) CREATE , DOES> @ ;
+7 CONST seven
+CREATE tbl 1 , 2 ,
+: ARRAY ( n -- ) CREATE CELLS ALLOT DOES> SWAP CELLS + ;
+3 ARRAY arr
+";
+        let prog = compile_with(src, &["-a", "att-asm32", "--allow-undefined"]).unwrap();
+        let header = |name: &str| prog.defs.iter().filter_map(|d| d.header.clone()).find(|h| h.name() == name).unwrap();
+        assert_eq!(header("seven"), ir::Header::Create { name: "seven".into(), action: Some("_L001".into()), unlisted: false });
+        assert_eq!(header("tbl"), ir::Header::Create { name: "tbl".into(), action: None, unlisted: false });
+        assert_eq!(testing::body_of(&prog, "seven"), [ir::Item::Cell(7)]);
+        assert_eq!(testing::body_of(&prog, "arr"), [ir::Item::Allot(12)]);
+
+        let asm = emit_with(src, &["-a", "att-asm32", "--allow-undefined"]).unwrap();
+        assert!(asm.contains("_L001:\n    call w_dodoes\n    .int w_fetch\n    .int w_exit\n"), "{asm}");
+        assert!(asm.contains("    HIGH_W w_seven 5 \"seven\" act=_L001\n    .int 7\n"), "{asm}");
+        assert!(asm.contains("    HIGH_W w_tbl 3 \"tbl\" act=w_do_var\n    .int 1\n    .int 2\n"), "{asm}");
+        assert!(asm.contains("    HIGH_W w_arr 3 \"arr\" act=_L002\n    .space 12\n"), "{asm}");
+    }
+
+    #[test]
+    fn does_needs_a_defining_word() {
+        let e = compile_error("DOES> 1 ;\n");
+        assert!(e.contains("DOES> outside of a defining word"), "{e}");
+    }
+
+    #[test]
+    fn backslash_comments_run_to_the_end_of_the_line() {
+        let src = "\
+\\ : gone ( -- ) ;
+: kept ( -- a b ) 1 \\ 2 3
+    4 ; \\ ;
+NEXT_HOST : two ( -- ) 2 , \\ 3 ,
+    ;
+VARIABLE v two \\";
+        let prog = compile_with(src, &["--stack-check", "error"]).unwrap();
+        assert_eq!(names(&prog), ["kept", "v"]);
+        let lits: Vec<_> = testing::body_of(&prog, "kept").iter().filter(|i| matches!(i, ir::Item::Literal(_))).collect();
+        assert_eq!(lits, [&ir::Item::Literal(1), &ir::Item::Literal(4)]);
+        assert_eq!(testing::body_of(&prog, "v"), [ir::Item::Cell(2)]);
+    }
+
+    #[test]
+    fn dot_paren_messages_are_not_compiled() {
+        let prog = compile_with(".( building ) : w ( -- ) .( inside ) ;\n", &[]).unwrap();
+        assert_eq!(names(&prog), ["w"]);
+        let e = compile_error(".(");
+        assert!(e.contains("Unterminated string for '.('"), "{e}");
+    }
 }
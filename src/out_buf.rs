@@ -0,0 +1,84 @@
+use std::io::{self, Write};
+
+/* Generated text is collected here and written to the sink a definition
+ * at a time, whenever a new header arrives.
+ *
+ * Write errors are kept until finish() so that the generators do not
+ * have to thread io::Result through every method.
+ */
+pub struct OutBuf {
+    sink: Box<dyn Write>,
+    chunks: Vec<String>,
+    error: Option<io::Error>,
+}
+
+impl OutBuf {
+    pub fn new(sink: Box<dyn Write>) -> Self {
+        OutBuf {
+            sink,
+            chunks: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn line(&mut self, l: impl Into<String>) {
+        let mut l = l.into();
+        l.push('\n');
+        self.chunks.push(l);
+    }
+
+    pub fn text(&mut self, t: impl Into<String>) {
+        self.chunks.push(t.into());
+    }
+
+    pub fn header(&mut self, l: impl Into<String>) {
+        self.flush();
+        self.line(l);
+    }
+
+    pub fn flush(&mut self) {
+        for c in self.chunks.drain(..) {
+            if self.error.is_none() {
+                if let Err(e) = self.sink.write_all(c.as_bytes()) {
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.flush();
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.sink.flush()
+    }
+}
+
+/* A sink that tests can read back once a generator is done with it. */
+#[cfg(test)]
+pub mod capture {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Capture {
+        pub fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::fs;
+
+use anyhow::Context;
+
+use crate::ir::{Header, Item, Program};
+use crate::parse_number;
+use crate::wordlists::FORTH;
+
+/* Peephole optimisation of the bodies of colon definitions and DOES>.
+ *
+ * A rule is a line `pattern => replacement`.  Both sides are words,
+ * and `lit N` stands for a compiled literal N.  The pattern only
+ * matches words that follow each other directly, so a label (a branch
+ * target) in between stops it, and the word after a `lit` (the operand
+ * of ['] and friends) never matches.  Rules are about the kernel's
+ * words, so only words found in FORTH match, and replacements are
+ * FORTH words too.  Lines starting with `\` or `#` are comments.
+ */
+const BUILTIN_RULES: &str = "
+lit 0 => zero
+lit 1 + => 1+
+swap drop => nip
+0= qbranch => tbranch
+";
+
+/* Rewrites allowed per definition for every call in it, so rules that
+ * undo each other cannot loop forever.
+ */
+const REWRITE_BUDGET: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Word(String),
+    Lit(i64),
+}
+
+impl Op {
+    fn matches(&self, body: &[Item], i: usize) -> bool {
+        match (self, &body[i]) {
+            (Op::Lit(n), Item::Literal(m)) => n == m,
+            (Op::Word(p), Item::Word(w, FORTH)) => {
+                let is_operand = i > 0 && matches!(&body[i - 1], Item::Word(l, _) if l.eq_ignore_ascii_case("lit"));
+                !is_operand && p.eq_ignore_ascii_case(w)
+            }
+            _ => false,
+        }
+    }
+
+    fn to_item(&self) -> Item {
+        match self {
+            Op::Word(w) => Item::Word(w.clone(), FORTH),
+            Op::Lit(n) => Item::Literal(*n),
+        }
+    }
+}
+
+pub struct Rule {
+    pattern: Vec<Op>,
+    replacement: Vec<Op>,
+}
+
+impl Rule {
+    fn parse_ops(text: &str) -> anyhow::Result<Vec<Op>> {
+        let mut ops = Vec::new();
+        let mut words = text.split_whitespace();
+        while let Some(w) = words.next() {
+            if w.eq_ignore_ascii_case("lit") {
+                let n = words.next().context("'lit' needs a number after it")?;
+                match parse_number(n)? {
+                    Some(n) => ops.push(Op::Lit(n)),
+                    None => anyhow::bail!("'{n}' after 'lit' is not a number"),
+                }
+            } else {
+                ops.push(Op::Word(w.to_string()));
+            }
+        }
+        Ok(ops)
+    }
+
+    fn parse(line: &str) -> anyhow::Result<Option<Rule>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('\\') || line.starts_with('#') {
+            return Ok(None);
+        }
+        let (pattern, replacement) = line.split_once("=>").context("Expected 'pattern => replacement'")?;
+        let pattern = Rule::parse_ops(pattern)?;
+        if pattern.is_empty() {
+            anyhow::bail!("Empty pattern");
+        }
+        let replacement = Rule::parse_ops(replacement)?;
+        Ok(Some(Rule { pattern, replacement }))
+    }
+
+    fn matches(&self, body: &[Item], i: usize) -> bool {
+        i + self.pattern.len() <= body.len() &&
+            self.pattern.iter().enumerate().all(|(k, op)| op.matches(body, i + k))
+    }
+}
+
+fn parse_rules(text: &str, source: &str) -> anyhow::Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let rule = Rule::parse(line).with_context(|| format!("{source}:{}: bad peephole rule", n + 1))?;
+        rules.extend(rule);
+    }
+    Ok(rules)
+}
+
+pub fn builtin_rules() -> anyhow::Result<Vec<Rule>> {
+    parse_rules(BUILTIN_RULES, "built-in rules")
+}
+
+pub fn load_rules(path: &str) -> anyhow::Result<Vec<Rule>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Cannot open '{path}'"))?;
+    parse_rules(&text, path)
+}
+
+fn optimize_body(body: &mut Vec<Item>, rules: &[Rule]) {
+    let longest = rules.iter().map(|r| r.pattern.len()).max().unwrap_or(1);
+    let mut budget = body.len() * REWRITE_BUDGET;
+    let mut i = 0;
+    while i < body.len() && budget > 0 {
+        match rules.iter().find(|r| r.matches(body, i)) {
+            None => i += 1,
+            Some(r) => {
+                let replacement: Vec<Item> = r.replacement.iter().map(Op::to_item).collect();
+                body.splice(i..i + r.pattern.len(), replacement);
+                i = i.saturating_sub(longest - 1);
+                budget -= 1;
+            }
+        }
+    }
+}
+
+pub fn optimize(prog: &mut Program, rules: &[Rule]) {
+    for d in &mut prog.defs {
+        if let Some(Header::Colon { .. } | Header::Does { .. }) = d.header {
+            optimize_body(&mut d.body, rules);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<Item> {
+        text.split_whitespace().map(|w| Item::Word(w.to_string(), FORTH)).collect()
+    }
+
+    fn rules(text: &str) -> Vec<Rule> {
+        parse_rules(text, "test").unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        let r = Rule::parse("lit 1 + => 1+").unwrap().unwrap();
+        assert_eq!(r.pattern, vec![Op::Lit(1), Op::Word("+".to_string())]);
+        assert_eq!(r.replacement, vec![Op::Word("1+".to_string())]);
+        let r = Rule::parse("drop drop =>").unwrap().unwrap();
+        assert!(r.replacement.is_empty());
+        assert!(Rule::parse("   ").unwrap().is_none());
+        assert!(Rule::parse("\\ a comment => x").unwrap().is_none());
+        assert!(Rule::parse("# a comment").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        assert!(Rule::parse("swap drop nip").is_err());
+        assert!(Rule::parse("=> nip").is_err());
+        assert!(Rule::parse("lit => zero").is_err());
+        assert!(Rule::parse("lit x => zero").is_err());
+    }
+
+    #[test]
+    fn rewrites_and_rescans() {
+        let mut body = vec![Item::Literal(1), Item::Word("+".to_string(), FORTH), Item::Word("swap".to_string(), FORTH),
+                            Item::Word("drop".to_string(), FORTH)];
+        optimize_body(&mut body, &builtin_rules().unwrap());
+        assert_eq!(body, words("1+ nip"));
+
+        let mut body = words("a b d");
+        optimize_body(&mut body, &rules("a b => c\nc d => e"));
+        assert_eq!(body, words("e"));
+    }
+
+    #[test]
+    fn skips_lit_operands() {
+        let mut body = words("lit swap drop");
+        optimize_body(&mut body, &builtin_rules().unwrap());
+        assert_eq!(body, words("lit swap drop"));
+    }
+
+    #[test]
+    fn stops_at_labels() {
+        let mut body = vec![Item::Word("swap".to_string(), FORTH), Item::Label("_L001".to_string()),
+                            Item::Word("drop".to_string(), FORTH)];
+        let before = body.clone();
+        optimize_body(&mut body, &builtin_rules().unwrap());
+        assert_eq!(body, before);
+    }
+
+    #[test]
+    fn loops_end() {
+        let mut body = words("a");
+        optimize_body(&mut body, &rules("a => b\nb => a"));
+        assert_eq!(body.len(), 1);
+    }
+}
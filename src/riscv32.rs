@@ -0,0 +1,208 @@
+use std::io::Write;
+
+use crate::{escape_quotes, Chains, EscapeMethod, FthGen, OutBuf, Threading};
+
+/* GNU as output for RV32.  Headers are written out in full:
+ *
+ *   w_foo:      .word <link>; .byte flags; .byte len; .ascii "name"
+ *   w_foo_cfa:  <code field>
+ *
+ * With indirect threading the code field is a `.word` pointing at the
+ * machine code.  With direct threading it *is* machine code: a
+ * `jal t0, <action>` for high-level words, so the action finds the body
+ * in t0, and the body itself for CODE words.  A DOES> body starts with
+ * `jal t1, w_dodoes`, so t1 has the thread and t0 (or W) the data.  Cells are always the
+ * address of a code field, and everything that can leave the location
+ * counter unaligned is followed by `.balign 4`.
+ */
+pub struct Rv32Gen {
+    threading: Threading,
+    last_dict_entry: String,
+    chains: Chains<String>,
+    out: OutBuf,
+}
+
+impl Rv32Gen {
+    pub fn new(out: Box<dyn Write>, threading: Threading) -> Self {
+        Rv32Gen {
+            threading,
+            last_dict_entry: String::from("0"),
+            chains: Chains::new(String::from("0")),
+            out: OutBuf::new(out),
+        }
+    }
+
+    fn code_field(&self, action: &str) -> String {
+        match self.threading {
+            Threading::Dtc => format!("    jal t0, {action}"),
+            _ => format!("    .word {action}"),
+        }
+    }
+
+    fn header(name: &str, sym: &str, flags: u8, code_field: &str, last_ref: &str) -> String {
+        let len = name.len();
+        let name = escape_quotes(EscapeMethod::Backslash, name);
+        let mut h = format!("    .balign 4\n{sym}:\n    .word {last_ref}\n    .byte {flags}\n    .byte {len}\n    .ascii \"{name}\"\n    .balign 4\n{sym}_cfa:");
+        if !code_field.is_empty() {
+            h.push('\n');
+            h.push_str(code_field);
+        }
+        h
+    }
+
+    fn start_header(&mut self, name: &str, sym: &str, is_immediate: bool, code_field: String, unlisted: bool) {
+        let last_ref = rv_ref(&self.last_dict_entry);
+        let flags = if is_immediate { 1 } else { 0 };
+        self.out.header(Rv32Gen::header(name, sym, flags, &code_field, &last_ref));
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+}
+
+fn rv_ref(s: &str) -> String {
+    if s == "0" {
+        return s.to_string();
+    }
+    format!("{s}_cfa")
+}
+
+impl FthGen for Rv32Gen {
+    fn prolog(&mut self) {
+    }
+
+    fn do_literal(&mut self, n: i64) {
+        self.out.line("    .word w_lit_cfa");
+        self.emit_cell(n);
+    }
+
+    fn do_string_literal(&mut self, s: &str) {
+        let s = escape_quotes(EscapeMethod::Backslash, s);
+        self.out.line(format!("    .ascii \"{s}\""));
+        self.out.line("    .balign 4");
+    }
+
+    fn create_word(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        let cf = self.code_field("w_docol");
+        self.start_header(w, sym, is_immediate, cf, unlisted);
+    }
+
+    fn create_code(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        let cf = match self.threading {
+            Threading::Dtc => String::new(),
+            _ => {
+                let code = format!("{sym}_code");
+                format!("    .word {code}\n{code}:")
+            }
+        };
+        self.start_header(w, sym, is_immediate, cf, unlisted);
+    }
+
+
+    fn close_definition(&mut self) {
+    }
+
+    fn emit_word(&mut self, _w: &str, sym: &str) {
+        let sym_ref = rv_ref(sym);
+        self.out.line(format!("    .word {sym_ref}"));
+    }
+
+    fn emit_cell(&mut self, n: i64) {
+        let l = n as i32;
+        self.out.line(format!("    .word {l}"));
+    }
+
+    fn emit_lines(&mut self, lines: Vec<String>) {
+        for l in lines {
+            self.out.text(l);
+        }
+    }
+
+    fn refer_to_label(&mut self, w: &str) {
+        self.out.line(format!("    .word {w}"));
+    }
+
+    fn emit_label(&mut self, l: &str) {
+        self.out.line(format!("{l}:"));
+    }
+
+    fn create_constant(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        let cf = self.code_field("w_const");
+        self.start_header(name, sym, false, cf, unlisted);
+        self.emit_cell(val);
+    }
+
+    fn create_variable(&mut self, name: &str, sym: &str, size: u8, unlisted: bool) {
+        let cf = self.code_field("w_var");
+        self.start_header(name, sym, false, cf, unlisted);
+        for _ in 0..size {
+            self.out.line("    .word 0");
+        }
+    }
+
+    fn set_wordlist(&mut self, head: &str) {
+        self.chains.switch(head, &mut self.last_dict_entry);
+    }
+
+    fn create_value(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        let cf = format!("{}\n{sym}_body:", self.code_field("w_const"));
+        self.start_header(name, sym, false, cf, unlisted);
+        self.emit_cell(val);
+    }
+
+    fn create_defer(&mut self, name: &str, sym: &str, action: Option<&str>, unlisted: bool) {
+        let cf = format!("{}\n{sym}_body:", self.code_field("w_defer"));
+        self.start_header(name, sym, false, cf, unlisted);
+        match action {
+            Some(a) => self.out.line(format!("    .word {}", rv_ref(a))),
+            None => self.out.line("    .word 0"),
+        }
+    }
+
+    fn refer_to_body(&mut self, sym: &str) {
+        self.out.line(format!("    .word {sym}_body"));
+    }
+
+    fn create_with_action(&mut self, name: &str, sym: &str, action: &str, unlisted: bool) {
+        let cf = self.code_field(action);
+        self.start_header(name, sym, false, cf, unlisted);
+    }
+
+    fn start_does(&mut self, label: &str) {
+        self.out.line("    .balign 4");
+        self.out.line(format!("{label}:"));
+        self.out.line("    jal t1, w_dodoes");
+    }
+
+    fn allot_space(&mut self, size: u64) {
+        self.out.line(format!("    .space {size}"));
+        self.out.line("    .balign 4");
+    }
+
+    fn epilog(&mut self) -> anyhow::Result<()> {
+        self.out.line("    .balign 4");
+        for (head, de) in self.chains.all(&self.last_dict_entry) {
+            self.out.line(format!("{head}: .word {}", rv_ref(&de)));
+        }
+        self.out.finish()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::out_buf::capture::Capture;
+
+    #[test]
+    fn cells_after_allot_are_aligned() {
+        let cap = Capture::default();
+        let mut g = Rv32Gen::new(Box::new(cap.clone()), Threading::Itc);
+        g.create_variable("buf", "w_buf", 0, false);
+        g.allot_space(3);
+        g.emit_cell(5);
+        g.epilog().unwrap();
+        assert!(cap.text().contains("    .space 3\n    .balign 4\n    .word 5\n"), "{}", cap.text());
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::{Header, Item, Program};
+use crate::{word_symbol, word_to_symbol};
+
+/* Dead-word elimination.  Only the definitions reachable from the roots
+ * are kept, along with everything outside a definition (the preamble,
+ * top-level VERBATIM blocks, ...), in their original order.  Because
+ * the generators only ever see the kept headers, their dictionary
+ * chains link those directly.  References are the words compiled into
+ * a definition, any defined symbol that shows up in its assembler
+ * text (CODE bodies, VERBATIM), the DOES> body a CREATE word runs and
+ * the word a DEFER starts out with.  Words are told apart by their
+ * symbols, so a name defined in two wordlists is two words; a root
+ * names every word of that name.
+ */
+
+/* Symbols of defined words mentioned in assembler text.  `_cfa` and
+ * `_code` suffixes are dropped so `w_foo_cfa` finds `w_foo`.
+ */
+fn text_refs(lines: &[String], defined: &HashSet<&str>, refs: &mut HashSet<String>) {
+    for l in lines {
+        for tok in l.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_')) {
+            let base = tok.strip_suffix("_cfa").or_else(|| tok.strip_suffix("_code")).unwrap_or(tok);
+            for t in [tok, base] {
+                if defined.contains(t) {
+                    refs.insert(t.to_string());
+                }
+            }
+        }
+    }
+}
+
+pub fn strip_unused(prog: &mut Program, roots: &[String]) -> anyhow::Result<()> {
+    let syms: Vec<Option<String>> = prog.defs.iter()
+        .map(|d| d.header.as_ref().map(|h| match h {
+            Header::Does { label } => word_to_symbol(label),
+            _ => word_symbol(d.wordlist, h.name()),
+        }))
+        .collect();
+    let mut by_sym: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, sym) in syms.iter().enumerate() {
+        if let Some(sym) = sym {
+            by_sym.entry(sym.as_str()).or_default().push(i);
+        }
+    }
+    let defined: HashSet<&str> = by_sym.keys().copied().collect();
+
+    let mut refs: Vec<HashSet<String>> = Vec::new();
+    for d in &prog.defs {
+        let mut r = HashSet::new();
+        match &d.header {
+            Some(Header::Create { action: Some(a), .. }) => {
+                r.insert(word_to_symbol(a));
+            }
+            Some(Header::Defer { action: Some((a, wid)), .. }) => {
+                r.insert(word_symbol(*wid, a));
+            }
+            _ => {}
+        }
+        for item in &d.body {
+            match item {
+                Item::Word(w, wid) | Item::BodyRef(w, wid) => {
+                    r.insert(word_symbol(*wid, w));
+                }
+                Item::Asm(lines) => text_refs(lines, &defined, &mut r),
+                _ => {}
+            }
+        }
+        refs.push(r);
+    }
+
+    let mut live = vec![false; prog.defs.len()];
+    let mut work: Vec<usize> = Vec::new();
+    for root in roots {
+        let sym = word_to_symbol(root);
+        let before = work.len();
+        for (i, d) in prog.defs.iter().enumerate() {
+            if d.header.as_ref().is_some_and(|h| word_to_symbol(h.name()) == sym) {
+                work.push(i);
+            }
+        }
+        if work.len() == before {
+            anyhow::bail!("--strip-unused root '{root}' is not defined");
+        }
+    }
+    for (i, sym) in syms.iter().enumerate() {
+        if sym.is_none() {
+            work.push(i);
+        }
+    }
+    while let Some(i) = work.pop() {
+        if live[i] {
+            continue;
+        }
+        live[i] = true;
+        for r in &refs[i] {
+            if let Some(defs) = by_sym.get(r.as_str()) {
+                work.extend(defs);
+            }
+        }
+    }
+
+    let mut live = live.into_iter();
+    prog.defs.retain(|_| live.next().unwrap_or(false));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{compile_with, names};
+
+    const SRC: &str = "\
+CODE helper ( -- )
+    jsr w_inner_cfa
+END-CODE
+CODE inner ( -- )
+END-CODE
+CODE unused ( -- )
+END-CODE
+: CONST ( n -- ) CREATE , DOES> @ ;
+7 CONST seven
+: action ( -- ) ;
+DEFER hook
+' action IS hook
+: main ( -- n ) helper hook seven ;
+: other ( -- ) unused ;
+";
+
+    fn kept(roots: &str) -> Vec<String> {
+        let prog = compile_with(SRC, &["--allow-undefined", "--stack-check", "off", "--strip-unused", roots]).unwrap();
+        names(&prog).into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn keeps_what_the_roots_reach() {
+        /* inner only through helper's assembler text, _L001 (the DOES>
+         * body) through seven and action through hook's initial action.
+         */
+        assert_eq!(kept("main"), ["helper", "inner", "_L001", "seven", "action", "hook", "main"]);
+        assert_eq!(kept("other"), ["unused", "other"]);
+        assert_eq!(kept("main,other").len(), 9);
+    }
+
+    #[test]
+    fn unknown_roots_are_errors() {
+        let Err(e) = compile_with(SRC, &["--allow-undefined", "--strip-unused", "main,nope"]) else {
+            panic!("an undefined root was accepted");
+        };
+        assert!(format!("{e:#}").contains("--strip-unused root 'nope' is not defined"), "{e:#}");
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use clap::ValueEnum;
+
+use crate::input_mgr::{Diagnostic, Diagnostics};
+use crate::ir::{Header, Item, Program};
+use crate::symbols::SymbolTable;
+use crate::word_symbol;
+
+/* Static checking of stack-effect comments.  A `( ... -- ... )` comment
+ * right after the name in `:`, CODE or EXTERN declares how many cells
+ * the word takes and leaves; only the number of items on each side
+ * counts.  Comments with `...`, `i*x`-style or `R:` items declare
+ * nothing.
+ *
+ * Each colon body is walked along every path the control words can
+ * take (qbranch, branch and the DO loop words with their label
+ * operands), tracking the depth relative to entry.  Paths have to
+ * agree wherever they meet, and at every `exit` the depth has to be
+ * what the declaration promises.  A body that calls a word whose
+ * effect is unknown is not checked.  An undeclared body that can be
+ * walked lends its effect to the words compiled after it.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum StackCheck {
+    Off,
+    Warn,
+    Error,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StackEffect {
+    pub ins: usize,
+    pub outs: usize,
+}
+
+impl fmt::Display for StackEffect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "( {} -- {} )", self.ins, self.outs)
+    }
+}
+
+pub fn parse_effect(text: &str) -> Option<StackEffect> {
+    let (ins, outs) = text.split_once("--")?;
+    let ins: Vec<&str> = ins.split_whitespace().collect();
+    let outs: Vec<&str> = outs.split_whitespace().collect();
+    let vague = |t: &&str| t.contains("...") || t.contains('*') || t.contains("--") ||
+        t.eq_ignore_ascii_case("R:") || *t == "|";
+    if ins.iter().any(vague) || outs.iter().any(vague) {
+        return None;
+    }
+    Some(StackEffect { ins: ins.len(), outs: outs.len() })
+}
+
+/* Words the control words compile on their own, for kernels that do
+ * not declare them.
+ */
+fn runtime_effect(w: &str) -> Option<StackEffect> {
+    let (ins, outs) = match w {
+        "2to_r" => (2, 0),
+        "2dup" => (2, 4),
+        "<>" | "equals" => (2, 1),
+        "over" => (2, 3),
+        "drop" => (1, 0),
+        "!" => (2, 0),
+        "type" => (2, 0),
+        "unloop" | "cr" => (0, 0),
+        _ => return None,
+    };
+    Some(StackEffect { ins, outs })
+}
+
+/* Lowest depth reached and depth at exit, or None if the body cannot
+ * be followed.
+ */
+fn walk(body: &[Item], effect_of: &dyn Fn(&str, usize) -> Option<StackEffect>) -> Result<Option<(i64, Option<i64>)>, String> {
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    for (i, item) in body.iter().enumerate() {
+        if let Item::Label(l) = item {
+            labels.insert(l.as_str(), i);
+        }
+    }
+    let target = |i: usize| -> Option<usize> {
+        match body.get(i) {
+            Some(Item::LabelRef(l)) => labels.get(l.as_str()).copied(),
+            _ => None,
+        }
+    };
+
+    let mut depth_at: Vec<Option<i64>> = vec![None; body.len() + 1];
+    let mut work: Vec<(usize, i64)> = vec![(0, 0)];
+    let mut min = 0;
+    let mut exit_depth: Option<i64> = None;
+
+    while let Some((pc, depth)) = work.pop() {
+        if pc > body.len() {
+            continue;
+        }
+        match depth_at[pc] {
+            Some(d) if d == depth => continue,
+            Some(d) => return Err(format!("has branches that meet with {d:+} and {depth:+} cells on the stack")),
+            None => depth_at[pc] = Some(depth),
+        }
+        min = min.min(depth);
+        let Some(item) = body.get(pc) else {
+            continue;
+        };
+        let mut next = |pc: usize, depth: i64| work.push((pc, depth));
+        match item {
+            Item::Literal(_) => next(pc + 1, depth + 1),
+            Item::Label(_) | Item::Str(_) => next(pc + 1, depth),
+            Item::Word(w, wid) => {
+                let lw = w.to_lowercase();
+                match lw.as_str() {
+                    "lit" => next(pc + 2, depth + 1),
+                    "exit" => {
+                        if exit_depth.is_some_and(|d| d != depth) {
+                            return Err(format!("returns with {:+} and with {depth:+} cells on the stack",
+                                               exit_depth.unwrap_or_default()));
+                        }
+                        exit_depth = Some(depth);
+                    }
+                    "abort" => {}
+                    "branch" | "qbranch" | "do_loop1" | "do_plus_loop1" => {
+                        let Some(t) = target(pc + 1) else {
+                            return Ok(None);
+                        };
+                        let depth = if lw == "qbranch" || lw == "do_plus_loop1" { depth - 1 } else { depth };
+                        min = min.min(depth);
+                        next(t, depth);
+                        if lw != "branch" {
+                            next(pc + 2, depth);
+                        }
+                    }
+                    _ => match effect_of(w, *wid) {
+                        None => return Ok(None),
+                        Some(e) => {
+                            let after_pop = depth - e.ins as i64;
+                            min = min.min(after_pop);
+                            next(pc + 1, after_pop + e.outs as i64);
+                        }
+                    },
+                }
+            }
+            Item::Cell(_) | Item::Asm(_) | Item::LabelRef(_) | Item::BodyRef(..) | Item::Allot(_) => return Ok(None),
+        }
+    }
+    Ok(Some((min, exit_depth)))
+}
+
+pub fn check(prog: &Program, symbols: &SymbolTable, mode: StackCheck) -> anyhow::Result<()> {
+    if mode == StackCheck::Off {
+        return Ok(());
+    }
+    let mut inferred: HashMap<String, StackEffect> = HashMap::new();
+    let mut found: Vec<Diagnostic> = Vec::new();
+
+    for d in &prog.defs {
+        let Some(Header::Colon { name, .. }) = &d.header else {
+            continue;
+        };
+        if !d.closed {
+            continue;
+        }
+        let sym = symbols.lookup(name, d.wordlist);
+        let declared = sym.and_then(|s| s.effect);
+        let effect_of = |w: &str, wid: usize| -> Option<StackEffect> {
+            if let Some(s) = symbols.lookup(w, wid) {
+                if s.effect.is_some() {
+                    return s.effect;
+                }
+                if let Some(e) = s.kind.data_effect() {
+                    return Some(e);
+                }
+            }
+            inferred.get(&word_symbol(wid, w)).copied()
+                .or_else(|| runtime_effect(&w.to_lowercase()))
+        };
+        let (msg, effect) = match walk(&d.body, &effect_of) {
+            Err(msg) => (Some(format!("'{name}' {msg}")), None),
+            Ok(None) | Ok(Some((_, None))) => (None, None),
+            Ok(Some((min, Some(exit)))) => {
+                let body = StackEffect { ins: (-min) as usize, outs: (exit - min) as usize };
+                match declared {
+                    Some(decl) if exit != decl.outs as i64 - decl.ins as i64 || body.ins > decl.ins => {
+                        let shown = if body.ins <= decl.ins {
+                            StackEffect { ins: decl.ins, outs: (decl.ins as i64 + exit) as usize }
+                        } else {
+                            body
+                        };
+                        (Some(format!("'{name}' is declared {decl} but its body is {shown}")), None)
+                    }
+                    _ => (None, Some(body)),
+                }
+            }
+        };
+        if declared.is_none() {
+            if let Some(e) = effect {
+                inferred.insert(word_symbol(d.wordlist, name), e);
+            }
+        }
+        if let Some(msg) = msg {
+            let loc = sym.and_then(|s| s.loc.clone());
+            match mode {
+                StackCheck::Error => found.push(Diagnostic::error(loc, msg)),
+                _ => eprintln!("{}", Diagnostic::warning(loc, msg)),
+            }
+        }
+    }
+    if !found.is_empty() {
+        return Err(Diagnostics(found).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn effect_of(w: &str, _wid: usize) -> Option<StackEffect> {
+        let (ins, outs) = match w {
+            "dup" => (1, 2),
+            "+" => (2, 1),
+            "i" => (0, 1),
+            _ => return runtime_effect(w),
+        };
+        Some(StackEffect { ins, outs })
+    }
+
+    fn body(text: &str) -> Vec<Item> {
+        text.split_whitespace()
+            .map(|w| {
+                if let Ok(n) = w.parse() {
+                    Item::Literal(n)
+                } else if let Some(l) = w.strip_prefix('>') {
+                    Item::LabelRef(l.to_string())
+                } else if let Some(l) = w.strip_suffix(':') {
+                    Item::Label(l.to_string())
+                } else {
+                    Item::Word(w.to_string(), 0)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_effects() {
+        assert_eq!(parse_effect("a b -- c"), Some(StackEffect { ins: 2, outs: 1 }));
+        assert_eq!(parse_effect(" -- "), Some(StackEffect { ins: 0, outs: 0 }));
+        assert_eq!(parse_effect("a b"), None);
+        assert_eq!(parse_effect("i*x -- j*x"), None);
+        assert_eq!(parse_effect("a ... -- b"), None);
+        assert_eq!(parse_effect("x -- R: x"), None);
+        assert_eq!(parse_effect("r: x --"), None);
+        assert_eq!(parse_effect("a -- b | c d"), None);
+    }
+
+    #[test]
+    fn walks_straight_code() {
+        assert_eq!(walk(&body("dup + exit"), &effect_of), Ok(Some((-1, Some(0)))));
+        assert_eq!(walk(&body("lit 5 + exit"), &effect_of), Ok(Some((-1, Some(0)))));
+        assert_eq!(walk(&body("dup unknown exit"), &effect_of), Ok(None));
+    }
+
+    #[test]
+    fn merges_branches() {
+        /* IF DUP ELSE 0 THEN */
+        assert_eq!(walk(&body("qbranch >a dup branch >b a: 0 b: exit"), &effect_of),
+                   Ok(Some((-2, Some(0)))));
+        /* IF 5 THEN */
+        assert_eq!(walk(&body("qbranch >a 5 a: exit"), &effect_of),
+                   Err("has branches that meet with +0 and -1 cells on the stack".to_string()));
+    }
+
+    #[test]
+    fn returns_with_one_depth() {
+        assert_eq!(walk(&body("qbranch >a 5 exit a: exit"), &effect_of),
+                   Err("returns with +0 and with -1 cells on the stack".to_string()));
+    }
+
+    #[test]
+    fn walks_do_loops() {
+        /* 10 0 DO I + LOOP */
+        assert_eq!(walk(&body("10 0 2to_r a: i + do_loop1 >a b: unloop exit"), &effect_of),
+                   Ok(Some((-1, Some(0)))));
+        /* 10 0 DO I 2 +LOOP leaves a cell behind each time round */
+        assert_eq!(walk(&body("10 0 2to_r a: i 2 do_plus_loop1 >a b: unloop exit"), &effect_of),
+                   Err("has branches that meet with +0 and +1 cells on the stack".to_string()));
+        /* 10 0 DO I DROP 2 +LOOP */
+        assert_eq!(walk(&body("10 0 2to_r a: i drop 2 do_plus_loop1 >a b: unloop exit"), &effect_of),
+                   Ok(Some((0, Some(0)))));
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::input_mgr::{Diagnostic, Diagnostics, Location};
+use crate::stack_check::StackEffect;
+use crate::{word_symbol, word_to_symbol};
+
+/* Every word the program defines or declares with EXTERN, keyed by the
+ * symbol the generators will use for it, so two spellings only count
+ * as the same word when they assemble to the same label, and the same
+ * name in two wordlists is two words.
+ *
+ * References are collected while interpreting and checked once the
+ * whole source has been read, because a definition may refer to a word
+ * that comes later (or only exists in the kernel).  Each use keeps the
+ * search order it was made with, and each symbol its wordlist.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymKind {
+    Colon,
+    Code,
+    Constant,
+    Variable,
+    Value,
+    Defer,
+    Created,
+    Extern,
+}
+
+impl SymKind {
+    /* CONSTANT, VARIABLE and VALUE words push one cell. */
+    pub fn data_effect(self) -> Option<StackEffect> {
+        match self {
+            SymKind::Constant | SymKind::Variable | SymKind::Value => Some(StackEffect { ins: 0, outs: 1 }),
+            _ => None,
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            SymKind::Colon => "a colon definition",
+            SymKind::Code => "a CODE word",
+            SymKind::Constant => "a constant",
+            SymKind::Variable => "a variable",
+            SymKind::Value => "a VALUE",
+            SymKind::Defer => "a DEFER",
+            SymKind::Created => "made by CREATE",
+            SymKind::Extern => "declared EXTERN",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymKind,
+    pub loc: Option<Location>,
+    pub effect: Option<StackEffect>,
+    pub wordlist: usize,
+}
+
+pub struct SymbolTable {
+    syms: HashMap<String, Symbol>,
+    uses: Vec<(String, Option<Location>, Rc<Vec<usize>>)>,
+}
+
+/* Suggestions further away than this many edits are not worth showing. */
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + if ca == *cb { 0 } else { 1 };
+            cur.push(sub.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable {
+            syms: HashMap::new(),
+            uses: Vec::new(),
+        }
+    }
+
+    pub fn define(&mut self, name: &str, kind: SymKind, loc: Option<Location>, wordlist: usize) {
+        let sym = Symbol { name: name.to_string(), kind, loc, effect: None, wordlist };
+        self.syms.insert(word_symbol(wordlist, name), sym);
+    }
+
+    pub fn forget(&mut self, name: &str, wordlist: usize) {
+        self.syms.remove(&word_symbol(wordlist, name));
+    }
+
+    pub fn lookup(&self, name: &str, wordlist: usize) -> Option<&Symbol> {
+        self.syms.get(&word_symbol(wordlist, name))
+    }
+
+    /* The word `name` stands for under a search order, innermost
+     * wordlist first.
+     */
+    pub fn find(&self, name: &str, order: &[usize]) -> Option<&Symbol> {
+        order.iter().rev().find_map(|&wid| self.lookup(name, wid))
+    }
+
+    pub fn set_effect(&mut self, name: &str, wordlist: usize, effect: StackEffect) {
+        if let Some(s) = self.syms.get_mut(&word_symbol(wordlist, name)) {
+            s.effect = Some(effect);
+        }
+    }
+
+    pub fn add_use(&mut self, name: &str, loc: Option<Location>, order: Rc<Vec<usize>>) {
+        self.uses.push((name.to_string(), loc, order));
+    }
+
+    /* Up to three defined names close to `name`, nearest first.  A name
+     * that differs only in case always comes first.
+     */
+    pub fn suggest(&self, name: &str) -> Vec<&Symbol> {
+        let lower = name.to_lowercase();
+        let mut found: Vec<(usize, &Symbol)> = self.syms.values()
+            .map(|s| {
+                let d = if s.name.to_lowercase() == lower { 0 } else { edit_distance(&lower, &s.name.to_lowercase()) };
+                (d, s)
+            })
+            .filter(|(d, _)| *d <= MAX_SUGGESTION_DISTANCE)
+            .collect();
+        found.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.name.cmp(&b.1.name)));
+        found.into_iter().take(3).map(|(_, s)| s).collect()
+    }
+
+    /* One diagnostic for every reference to a word that is neither
+     * defined nor declared EXTERN, or whose wordlist was not in the
+     * search order.
+     */
+    pub fn check(&self, wordlists: &[String]) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        for (name, loc, order) in &self.uses {
+            if self.find(name, order).is_some() {
+                continue;
+            }
+            let sym = word_to_symbol(name);
+            let hidden = self.syms.values()
+                .filter(|s| word_to_symbol(&s.name) == sym)
+                .min_by_key(|s| s.wordlist);
+            let mut msg = match hidden {
+                Some(s) => format!("'{name}' is in {}, which is not in the search order", wordlists[s.wordlist]),
+                None => format!("Undefined word '{name}'"),
+            };
+            let suggestions = if hidden.is_some() { Vec::new() } else { self.suggest(name) };
+            if !suggestions.is_empty() {
+                let quoted: Vec<String> = suggestions.iter().map(|s| format!("'{}'", s.name)).collect();
+                msg.push_str(&format!("; did you mean {}?", quoted.join(" or ")));
+                for s in suggestions {
+                    if let Some(l) = &s.loc {
+                        msg.push_str(&format!("\n    note: '{}' is {} at {l}", s.name, s.kind.describe()));
+                    }
+                }
+            }
+            errors.push(Diagnostic::error(loc.clone(), msg));
+        }
+        if !errors.is_empty() {
+            return Err(Diagnostics(errors).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("swap", "swap"), 0);
+        assert_eq!(edit_distance("", "dup"), 3);
+        assert_eq!(edit_distance("dup", ""), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("swap", "swop"), 1);
+    }
+
+    #[test]
+    fn search_order_finds_innermost_first() {
+        let mut t = SymbolTable::new();
+        t.define("x", SymKind::Colon, None, 0);
+        t.define("x", SymKind::Constant, None, 2);
+        assert_eq!(t.find("x", &[0, 2]).map(|s| s.kind), Some(SymKind::Constant));
+        assert_eq!(t.find("x", &[2, 0]).map(|s| s.kind), Some(SymKind::Colon));
+        assert_eq!(t.find("x", &[1]).map(|s| s.kind), None);
+        assert_eq!(t.lookup("x", 2).map(|s| s.kind), Some(SymKind::Constant));
+    }
+
+    #[test]
+    fn suggestions_nearest_first() {
+        let mut t = SymbolTable::new();
+        for name in ["swpa", "sway", "SWAP", "rot", "swop"] {
+            t.define(name, SymKind::Colon, None, 0);
+        }
+        let names: Vec<&str> = t.suggest("swap").iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["SWAP", "sway", "swop"]);
+        let names: Vec<&str> = t.suggest("Sway").iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names[0], "sway");
+        assert!(t.suggest("emit").is_empty());
+    }
+}
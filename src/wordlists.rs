@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::{word_to_symbol, Fth, FthAction};
+
+/* Wordlists only exist while rfc runs.  Each one becomes a link chain
+ * of its own in the generated dictionary, ending in the symbol from
+ * heads(); FORTH keeps `dict_head`.  DEFINITIONS picks the chain new
+ * headers go on, and the search order decides which words the source
+ * may use, which SymbolTable::check() reports on.
+ *
+ * The words below only act in interpret state.  Inside a definition
+ * they are compiled like any other word, so the kernel can still have
+ * a search order of its own at run time.
+ */
+pub const FORTH: usize = 0;
+
+pub struct SearchOrder {
+    names: Vec<String>,
+    heads: Vec<String>,
+    vocabularies: HashMap<String, usize>,
+    order: Rc<Vec<usize>>,
+    current: usize,
+}
+
+lazy_static! {
+    pub(crate) static ref SEARCH_WORDS: HashMap<&'static str, FthAction> = {
+        let mut m = HashMap::new();
+
+        m.insert("WORDLIST", w_wordlist as FthAction);
+        m.insert("VOCABULARY", w_vocabulary as FthAction);
+        m.insert("FORTH-WORDLIST", w_forth_wordlist as FthAction);
+        m.insert("DEFINITIONS", w_definitions as FthAction);
+        m.insert("GET-CURRENT", w_get_current as FthAction);
+        m.insert("SET-CURRENT", w_set_current as FthAction);
+        m.insert("ALSO", w_also as FthAction);
+        m.insert("ONLY", w_only as FthAction);
+        m.insert("PREVIOUS", w_previous as FthAction);
+
+        m
+    };
+}
+
+impl SearchOrder {
+    pub fn new() -> Self {
+        let mut vocabularies = HashMap::new();
+        vocabularies.insert(String::from("FORTH"), FORTH);
+        SearchOrder {
+            names: vec![String::from("FORTH")],
+            heads: vec![String::from("dict_head")],
+            vocabularies,
+            order: Rc::new(vec![FORTH]),
+            current: FORTH,
+        }
+    }
+
+    fn add(&mut self, name: String, head: String) -> usize {
+        self.names.push(name);
+        self.heads.push(head);
+        self.names.len() - 1
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /* Head symbols of all wordlists, indexed by wordlist id. */
+    pub fn heads(&self) -> Vec<String> {
+        self.heads.clone()
+    }
+
+    /* The search order, innermost last. */
+    pub fn order(&self) -> Rc<Vec<usize>> {
+        self.order.clone()
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn vocabulary(&self, upper_w: &str) -> Option<usize> {
+        self.vocabularies.get(upper_w).copied()
+    }
+
+    /* What executing a VOCABULARY word does: replace the top of the
+     * search order.
+     */
+    pub fn select(&mut self, wid: usize) {
+        let order = Rc::make_mut(&mut self.order);
+        order.pop();
+        order.push(wid);
+    }
+
+    fn top(&self) -> usize {
+        *self.order.last().unwrap_or(&FORTH)
+    }
+}
+
+fn pop_wid(fth: &mut Fth, for_word: &str) -> anyhow::Result<usize> {
+    let v = match fth.data_stack.pop() {
+        None => anyhow::bail!("Stack underflow for {for_word}"),
+        Some(v) => v,
+    };
+    match usize::try_from(v) {
+        Ok(wid) if wid < fth.search.names.len() => Ok(wid),
+        _ => anyhow::bail!("{v} is not a wordlist for {for_word}"),
+    }
+}
+
+fn set_current(fth: &mut Fth, wid: usize) {
+    fth.search.current = wid;
+    fth.ir.set_wordlist(wid);
+}
+
+fn w_wordlist(fth: &mut Fth) -> anyhow::Result<()> {
+    let n = fth.search.names.len();
+    let wid = fth.search.add(format!("wordlist #{n}"), format!("dict_head_{n}"));
+    fth.data_stack.push(wid as i64);
+
+    Ok(())
+}
+
+fn w_vocabulary(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.input_mgr.skip_ws()?;
+    let name = fth.input_mgr.word()?;
+    let Some(name) = name else {
+        anyhow::bail!("EOF after VOCABULARY");
+    };
+    let upper_name = name.to_uppercase();
+    if fth.search.vocabularies.contains_key(&upper_name) {
+        anyhow::bail!("Vocabulary {name} already exists");
+    }
+    let head = format!("dict_head_{}", &word_to_symbol(&name)[2..]);
+    let wid = fth.search.add(name, head);
+    fth.search.vocabularies.insert(upper_name, wid);
+
+    Ok(())
+}
+
+fn w_forth_wordlist(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.data_stack.push(FORTH as i64);
+
+    Ok(())
+}
+
+fn w_definitions(fth: &mut Fth) -> anyhow::Result<()> {
+    let wid = fth.search.top();
+    set_current(fth, wid);
+
+    Ok(())
+}
+
+fn w_get_current(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.data_stack.push(fth.search.current as i64);
+
+    Ok(())
+}
+
+fn w_set_current(fth: &mut Fth) -> anyhow::Result<()> {
+    let wid = pop_wid(fth, "SET-CURRENT")?;
+    set_current(fth, wid);
+
+    Ok(())
+}
+
+fn w_also(fth: &mut Fth) -> anyhow::Result<()> {
+    let top = fth.search.top();
+    Rc::make_mut(&mut fth.search.order).push(top);
+
+    Ok(())
+}
+
+fn w_only(fth: &mut Fth) -> anyhow::Result<()> {
+    fth.search.order = Rc::new(vec![FORTH]);
+
+    Ok(())
+}
+
+fn w_previous(fth: &mut Fth) -> anyhow::Result<()> {
+    if fth.search.order.len() < 2 {
+        anyhow::bail!("PREVIOUS would leave the search order empty");
+    }
+    Rc::make_mut(&mut fth.search.order).pop();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Program;
+    use crate::testing::compile_with;
+
+    fn wordlist_of(prog: &Program, name: &str) -> usize {
+        prog.defs.iter().find(|d| d.header.as_ref().is_some_and(|h| h.name() == name)).unwrap().wordlist
+    }
+
+    fn error(src: &str) -> String {
+        match compile_with(src, &[]) {
+            Ok(_) => panic!("{src:?} compiled"),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    #[test]
+    fn select_replaces_the_top_of_the_order() {
+        let mut s = SearchOrder::new();
+        let a = s.add("a".into(), "dict_head_a".into());
+        s.select(a);
+        assert_eq!(*s.order(), [a]);
+        assert_eq!(s.heads(), ["dict_head", "dict_head_a"]);
+        assert_eq!(s.current(), FORTH);
+    }
+
+    #[test]
+    fn definitions_go_to_the_current_wordlist() {
+        let src = "\
+VOCABULARY voc
+WORDLIST CONSTANT anon
+ALSO voc DEFINITIONS
+: in_voc ( -- ) ;
+anon SET-CURRENT
+: in_anon ( -- ) ;
+FORTH-WORDLIST SET-CURRENT
+: in_forth ( -- ) ;
+ONLY
+: again ( -- ) in_forth ;
+";
+        let prog = compile_with(src, &[]).unwrap();
+        assert_eq!(wordlist_of(&prog, "in_voc"), 1);
+        assert_eq!(wordlist_of(&prog, "in_anon"), 2);
+        assert_eq!(wordlist_of(&prog, "in_forth"), FORTH);
+        assert_eq!(wordlist_of(&prog, "again"), FORTH);
+    }
+
+    #[test]
+    fn only_drops_vocabularies_from_the_order() {
+        let e = error("VOCABULARY voc\nALSO voc DEFINITIONS\n: w ( -- ) ;\nONLY\n: u ( -- ) w ;\n");
+        assert!(e.contains("'w' is in voc, which is not in the search order"), "{e}");
+    }
+
+    #[test]
+    fn misuse_is_reported() {
+        assert!(error("PREVIOUS\n").contains("PREVIOUS would leave the search order empty"));
+        assert!(error("VOCABULARY v\nVOCABULARY V\n").contains("Vocabulary V already exists"));
+        assert!(error("5 SET-CURRENT\n").contains("5 is not a wordlist for SET-CURRENT"));
+        assert!(error("SET-CURRENT\n").contains("Stack underflow for SET-CURRENT"));
+    }
+}
@@ -0,0 +1,248 @@
+use std::io::Write;
+
+use crate::{Chains, FthGen, OutBuf, Threading};
+
+/* Z80 output in the common subset of sjasmplus and z80asm syntax.
+ * Headers are written out in full rather than through kernel macros:
+ *
+ *   w_foo:      DW <link>, DB flags, DB len, DEFB "name"
+ *   w_foo_cfa:  DW <code field>
+ *
+ * The link is the code field of the previous listed word, the same
+ * chain Ca6502 builds with `.cfa`.  CODE bodies follow their code field
+ * directly and end in the kernel's NEXT macro.
+ *
+ * With `--threading dtc` the code field is `CALL <action>` instead of a
+ * DW, and CODE bodies start right at `_cfa`.
+ */
+pub struct Z80Gen {
+    threading: Threading,
+    last_dict_entry: String,
+    chains: Chains<String>,
+    out: OutBuf,
+}
+
+impl Z80Gen {
+    pub fn new(out: Box<dyn Write>, threading: Threading) -> Self {
+        Z80Gen {
+            threading,
+            last_dict_entry: String::from("0"),
+            chains: Chains::new(String::from("0")),
+            out: OutBuf::new(out),
+        }
+    }
+
+    fn header(name: &str, sym: &str, flags: u8, code_field: &str, last_ref: &str) -> String {
+        let len = name.len();
+        let name = z80_string(name);
+        let mut h = format!("{sym}:\n    DW {last_ref}\n    DB {flags}\n    DB {len}\n    DEFB {name}\n{sym}_cfa:");
+        if !code_field.is_empty() {
+            h.push('\n');
+            h.push_str(code_field);
+        }
+        h
+    }
+
+    fn code_field(&self, action: &str) -> String {
+        match self.threading {
+            Threading::Dtc => format!("    CALL {action}"),
+            _ => format!("    DW {action}"),
+        }
+    }
+
+    fn start_header(&mut self, name: &str, sym: &str, is_immediate: bool, code_field: String, unlisted: bool) {
+        let last_ref = z80_ref(&self.last_dict_entry);
+        let flags = if is_immediate { 1 } else { 0 };
+        self.out.header(Z80Gen::header(name, sym, flags, &code_field, &last_ref));
+        if !unlisted {
+            self.last_dict_entry = sym.to_string();
+        }
+    }
+}
+
+fn z80_ref(s: &str) -> String {
+    if s == "0" {
+        return s.to_string();
+    }
+    format!("{s}_cfa")
+}
+
+/* Quoted runs for the printable characters, plain byte values for
+ * anything else (including the quote itself), comma separated.
+ */
+fn z80_string(s: &str) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut run = String::new();
+
+    for b in s.bytes() {
+        if b == b'"' || !(b' '..=b'~').contains(&b) {
+            if !run.is_empty() {
+                parts.push(format!("\"{run}\""));
+                run.clear();
+            }
+            parts.push(b.to_string());
+        } else {
+            run.push(char::from(b));
+        }
+    }
+    if !run.is_empty() || parts.is_empty() {
+        parts.push(format!("\"{run}\""));
+    }
+    parts.join(", ")
+}
+
+impl FthGen for Z80Gen {
+    fn prolog(&mut self) {
+    }
+
+    fn do_literal(&mut self, n: i64) {
+        self.out.line("    DW w_lit_cfa");
+        self.emit_cell(n);
+    }
+
+    fn do_string_literal(&mut self, s: &str) {
+        self.out.line(format!("    DEFB {}", z80_string(s)));
+    }
+
+    fn create_word(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        let cf = self.code_field("w_docol");
+        self.start_header(w, sym, is_immediate, cf, unlisted);
+    }
+
+    fn create_code(&mut self, w: &str, sym: &str, is_immediate: bool, unlisted: bool) {
+        let cf = match self.threading {
+            Threading::Dtc => String::new(),
+            _ => {
+                let code = format!("{sym}_code");
+                format!("    DW {code}\n{code}:")
+            }
+        };
+        self.start_header(w, sym, is_immediate, cf, unlisted);
+    }
+
+
+    fn close_definition(&mut self) {
+    }
+
+    fn emit_word(&mut self, _w: &str, sym: &str) {
+        let sym_ref = z80_ref(sym);
+        self.out.line(format!("    DW {sym_ref}"));
+    }
+
+    fn emit_cell(&mut self, n: i64) {
+        let l = n as i16;
+        self.out.line(format!("    DW {l}"));
+    }
+
+    fn emit_lines(&mut self, lines: Vec<String>) {
+        for l in lines {
+            self.out.text(l);
+        }
+    }
+
+    fn refer_to_label(&mut self, w: &str) {
+        self.out.line(format!("    DW {w}"));
+    }
+
+    fn emit_label(&mut self, l: &str) {
+        self.out.line(format!("{l}:"));
+    }
+
+    fn create_constant(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        let cf = self.code_field("w_const");
+        self.start_header(name, sym, false, cf, unlisted);
+        self.emit_cell(val);
+    }
+
+    fn create_variable(&mut self, name: &str, sym: &str, size: u8, unlisted: bool) {
+        let cf = self.code_field("w_var");
+        self.start_header(name, sym, false, cf, unlisted);
+        for _ in 0..size {
+            self.out.line("    DW 0");
+        }
+    }
+
+    fn set_wordlist(&mut self, head: &str) {
+        self.chains.switch(head, &mut self.last_dict_entry);
+    }
+
+    fn create_value(&mut self, name: &str, sym: &str, val: i64, unlisted: bool) {
+        let cf = format!("{}\n{sym}_body:", self.code_field("w_const"));
+        self.start_header(name, sym, false, cf, unlisted);
+        self.emit_cell(val);
+    }
+
+    fn create_defer(&mut self, name: &str, sym: &str, action: Option<&str>, unlisted: bool) {
+        let cf = format!("{}\n{sym}_body:", self.code_field("w_defer"));
+        self.start_header(name, sym, false, cf, unlisted);
+        match action {
+            Some(a) => self.out.line(format!("    DW {}", z80_ref(a))),
+            None => self.out.line("    DW 0"),
+        }
+    }
+
+    fn refer_to_body(&mut self, sym: &str) {
+        self.out.line(format!("    DW {sym}_body"));
+    }
+
+    fn create_with_action(&mut self, name: &str, sym: &str, action: &str, unlisted: bool) {
+        let cf = self.code_field(action);
+        self.start_header(name, sym, false, cf, unlisted);
+    }
+
+    fn start_does(&mut self, label: &str) {
+        self.out.line(format!("{label}:"));
+        self.out.line("    CALL w_dodoes");
+    }
+
+    fn allot_space(&mut self, size: u64) {
+        self.out.line(format!("    DEFS {size}"));
+    }
+
+    fn epilog(&mut self) -> anyhow::Result<()> {
+        for (head, de) in self.chains.all(&self.last_dict_entry) {
+            self.out.line(format!("{head}:\n    DW {}", z80_ref(&de)));
+        }
+        self.out.finish()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::z80_string;
+    use crate::testing::emit_with;
+
+    const SRC: &str = "\
+CODE dup ( a -- a a )
+    ld a,(hl)
+END-CODE
+: t ( a -- a a ) dup ; IMMEDIATE
+: s ( -- a n ) s\" hi\" ;
+";
+
+    #[test]
+    fn strings_quote_only_printable_ascii() {
+        assert_eq!(z80_string("hi"), "\"hi\"");
+        assert_eq!(z80_string("a\"b"), "\"a\", 34, \"b\"");
+        assert_eq!(z80_string("é"), "195, 169");
+        assert_eq!(z80_string(""), "\"\"");
+    }
+
+    #[test]
+    fn itc_headers_link_code_fields() {
+        let asm = emit_with(SRC, &["-a", "z80"]).unwrap();
+        assert!(asm.contains("w_dup:\n    DW 0\n    DB 0\n    DB 3\n    DEFB \"dup\"\nw_dup_cfa:\n    DW w_dup_code\nw_dup_code:\n    ld a,(hl)\n    NEXT\n"), "{asm}");
+        assert!(asm.contains("w_t:\n    DW w_dup_cfa\n    DB 1\n    DB 1\n    DEFB \"t\"\nw_t_cfa:\n    DW w_docol\n    DW w_dup_cfa\n    DW w_exit_cfa\n"), "{asm}");
+        assert!(asm.contains("_L002:\n    DEFB \"hi\"\n"), "{asm}");
+        assert!(asm.ends_with("dict_head:\n    DW w_s_cfa\n"), "{asm}");
+    }
+
+    #[test]
+    fn dtc_code_fields_are_calls() {
+        let asm = emit_with(SRC, &["-a", "z80", "-t", "dtc"]).unwrap();
+        assert!(asm.contains("w_dup_cfa:\n    ld a,(hl)\n"), "{asm}");
+        assert!(asm.contains("w_t_cfa:\n    CALL w_docol\n    DW w_dup_cfa\n"), "{asm}");
+    }
+}